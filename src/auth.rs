@@ -0,0 +1,88 @@
+use actix_web::HttpRequest;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::jwt::Verifier;
+use crate::models::AppError;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// Per-image capability issued at completion with `DELETE_TOKENS`
+pub const DELETE_TOKEN_HEADER: &str = "X-Delete-Token";
+
+/// Scopes a bearer token needs per route; API keys carry all of them
+pub const SCOPE_TRANSFER_WRITE: &str = "transfer:write";
+pub const SCOPE_IMAGE_READ: &str = "image:read";
+pub const SCOPE_IMAGE_WRITE: &str = "image:write";
+pub const SCOPE_IMAGE_DELETE: &str = "image:delete";
+pub const SCOPE_ADMIN: &str = "admin";
+
+/// Compares secrets without short-circuiting on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Resolves the `X-Api-Key` header to its configured key id.
+/// Fails closed: with no `API_KEYS` configured every gated route is rejected.
+pub fn require_api_key(req: &HttpRequest, config: &Config) -> Result<String, AppError> {
+    let presented = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| AppError::Unauthorized("Missing API key".into()))?;
+
+    config
+        .api_keys
+        .iter()
+        .find(|(_, secret)| constant_time_eq(secret.as_bytes(), presented.as_bytes()))
+        .map(|(id, _)| id.clone())
+        .ok_or_else(|| AppError::Unauthorized("Invalid API key".into()))
+}
+
+/// Resolves the caller from `X-Api-Key` or, when JWT auth is configured, an
+/// `Authorization: Bearer` token that must carry `scope`. Returns the key id, or `jwt:{sub}`.
+pub fn authorize(
+    req: &HttpRequest,
+    config: &Config,
+    jwt: Option<&Verifier>,
+    scope: &str,
+) -> Result<String, AppError> {
+    let bearer = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    let (Some(verifier), Some(token), None) = (jwt, bearer, req.headers().get(API_KEY_HEADER)) else {
+        return require_api_key(req, config);
+    };
+
+    let claims = verifier.verify(token.trim()).map_err(|e| {
+        log::debug!("Rejected bearer token: {e}");
+        AppError::Unauthorized("Invalid token".into())
+    })?;
+    if !claims.has_scope(scope) {
+        return Err(AppError::Forbidden(format!("Token lacks scope {scope}")));
+    }
+    // Without a subject every such token would share one caller id
+    let sub = claims
+        .sub
+        .filter(|sub| !sub.is_empty())
+        .ok_or_else(|| AppError::Unauthorized("Token has no sub".into()))?;
+    Ok(format!("jwt:{sub}"))
+}
+
+/// What is stored for a delete token: its SHA-256, hex-encoded. The token itself is only ever
+/// shown to the uploader.
+pub fn hash_delete_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether the `X-Delete-Token` header matches an image's stored token hash
+pub fn delete_token_matches(req: &HttpRequest, stored_hash: &str) -> bool {
+    req.headers()
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|token| {
+            constant_time_eq(hash_delete_token(token.trim()).as_bytes(), stored_hash.as_bytes())
+        })
+}
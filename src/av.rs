@@ -0,0 +1,31 @@
+use actix_web::web::Bytes;
+use clamav_client::tokio::{scan_stream, Tcp};
+use futures_util::Stream;
+use std::io;
+
+pub enum Verdict {
+    Clean,
+    /// Raw clamd reply, e.g. `stream: Eicar-Signature FOUND`
+    Infected(String),
+}
+
+/// Streams bytes to clamd over TCP using the INSTREAM protocol
+pub async fn scan<S>(addr: &str, body: S) -> Result<Verdict, String>
+where
+    S: Stream<Item = Result<Bytes, io::Error>>,
+{
+    let response = scan_stream(body, Tcp { host_address: addr }, None)
+        .await
+        .map_err(|e| format!("clamd: {e}"))?;
+
+    let clean = clamav_client::clean(&response).map_err(|e| format!("clamd reply: {e}"))?;
+    if clean {
+        Ok(Verdict::Clean)
+    } else {
+        let reply = String::from_utf8_lossy(&response)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string();
+        Ok(Verdict::Infected(reply))
+    }
+}
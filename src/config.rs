@@ -1,4 +1,19 @@
+use ipnet::IpNet;
+use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
+
+/// Which uploads a `RETENTION_POLICY` rule covers
+pub enum RetentionClass {
+    /// Uploads made without credentials
+    Anonymous,
+    Authenticated,
+    /// One caller: an API key id or `jwt:{sub}`
+    Caller(String),
+    /// A format pattern, as in `ALLOWED_FORMATS`
+    Format(String),
+    Any,
+}
 
 pub struct Config {
     pub r2_endpoint: String,
@@ -6,13 +21,301 @@ pub struct Config {
     pub r2_access_key: String,
     pub r2_secret_key: String,
     pub r2_public_domain: String,
+    /// CDN in front of the bucket; becomes the primary `url` when set
+    pub r2_cdn_domain: Option<String>,
+    /// Cheaper bucket originals are moved to after `cold_after_days`
+    pub r2_cold_bucket: Option<String>,
+    /// Public domain of `r2_cold_bucket`; set whenever it is
+    pub r2_cold_public_domain: String,
+    pub cold_after_days: u32,
+    /// Shape of image `url`s once dimensions are known: `{domain}`, `{key}`, `{w}` and `{h}` placeholders
+    pub url_template: Option<String>,
+    pub s3_region: String,
+    /// SDK defaults apply when unset
+    pub s3_connect_timeout_ms: Option<u64>,
+    pub s3_operation_timeout_ms: Option<u64>,
     pub mongo_uri: String,
-    pub redis_url: String,
+    /// Driver defaults apply to any of these left unset
+    pub mongo_max_pool_size: Option<u32>,
+    pub mongo_connect_timeout_ms: Option<u64>,
+    pub mongo_server_selection_timeout_ms: Option<u64>,
+    /// Image collections: 1 keeps everything in `i`, more spread documents over `i_00`..`i_ff`
+    pub mongo_shard_count: usize,
+    /// `None` when `CACHE_ENABLED=false`: no Redis, pending transfers are kept in MongoDB
+    pub redis_url: Option<String>,
     pub port: u16,
+    /// Path every route is mounted under, `""` or `/segment[/...]` without a trailing slash
+    pub route_prefix: String,
     pub max_size: u64,
     pub max_size_mb: u64,
     pub encryption_key: [u8; 32],
+    /// Per-API-key payload keys from `TENANT_ENCRYPTION_KEYS=id:hex,...`, used instead of
+    /// `encryption_key` for callers presenting that key
+    pub tenant_encryption_keys: HashMap<String, [u8; 32]>,
+    /// `false` serves `GET /i/{id}` payloads as plain JSON instead of AES-GCM encrypted hex
+    pub obfuscate: bool,
+    pub payload_versioned: bool,
+    /// Gzip the payload JSON before encrypting it
+    pub compress_payload: bool,
     pub allowed_formats: Vec<String>,
+    /// Patterns rejected even when `allowed_formats` matches them
+    pub blocked_formats: Vec<String>,
+    /// Default lifetimes in seconds (`None` = kept forever) for uploads that don't set
+    /// `expires_in_secs`; the first matching rule wins
+    pub retention_policy: Vec<(RetentionClass, Option<u64>)>,
+    pub extract_meta: bool,
+    pub request_timeout_secs: u64,
+    /// Idle seconds before a client connection is closed (`None` = actix default, 0 = no keep-alive)
+    pub http_keep_alive_secs: Option<u64>,
+    /// Accept cleartext HTTP/2 (h2c prior knowledge) next to HTTP/1.1 on the same port
+    pub http2: bool,
+    /// Responses smaller than this are sent uncompressed
+    pub compress_min_bytes: u64,
+    /// Redirect plain-HTTP requests to HTTPS and send HSTS on HTTPS responses (probes exempt)
+    pub force_https: bool,
+    pub hsts_max_age_secs: u64,
+    pub redis_key_prefix: String,
+    /// Redis namespace of pending transfers, i.e. `{prefix}{pending_namespace}:{id}`
+    pub pending_namespace: String,
+    pub infer_content_type: bool,
+    /// `(key id, secret)` pairs from `API_KEYS=id:secret,...`
+    pub api_keys: Vec<(String, String)>,
+    /// PEM public key that signs bearer tokens; takes precedence over `jwt_jwks_url`
+    pub jwt_public_key: Option<String>,
+    pub jwt_jwks_url: Option<String>,
+    /// Expected `iss` / `aud` claims, checked only when set
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    /// Gate `POST /transfer` and its completion behind an API key or `transfer:write` token
+    pub require_transfer_auth: bool,
+    /// In-flight request cap per API key (0 = unlimited)
+    pub key_concurrency: usize,
+    /// Per-key caps from `KEY_CONCURRENCY_OVERRIDES=id:n,...`, taking precedence over `key_concurrency`
+    pub key_concurrency_overrides: HashMap<String, usize>,
+    pub blocked_filenames: Vec<String>,
+    pub lowercase_keys: bool,
+    /// Store under an ASCII-only key and keep the client's file name as `orig_name`
+    pub safe_keys: bool,
+    /// Normalize file names to NFKC instead of NFC, folding compatibility forms (`ﬁ` → `fi`, full-width → ASCII)
+    pub name_nfkc: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub variant_widths: Vec<u32>,
+    /// Lossy quality (1-100) for WebP variants: `VARIANT_QUALITY`, else `IMAGE_QUALITY`
+    pub variant_quality: u8,
+    /// Send image clients that accept WebP to a full-size WebP copy, rendered on first request
+    pub webp_negotiation: bool,
+    pub dominant_color: bool,
+    pub phash: bool,
+    pub max_concurrent_processing: usize,
+    /// Memory budget for one decoded image (`MAX_DECODE_MB`)
+    pub max_decode_bytes: u64,
+    /// Intake constraints on upright raster dimensions; `None` = unconstrained
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    /// Required width:height, e.g. `(16, 9)`
+    pub aspect_ratio: Option<(u32, u32)>,
+    /// Wall-clock budget for decoding and encoding one image (`MAX_DECODE_MS`, 0 = unlimited)
+    pub max_decode_ms: u64,
+    pub clamav_addr: Option<String>,
+    pub clamav_timeout_secs: u64,
+    pub clamav_fail_open: bool,
+    /// `None` (unset or `0`) means unlimited
+    pub max_total_images: Option<u64>,
+    /// `Cache-Control` stored on uploaded objects and their variants
+    pub object_cache_control: Option<String>,
+    /// Fixed `x-amz-meta-*` pairs from `OBJECT_METADATA=name:value,...`
+    pub object_metadata: HashMap<String, String>,
+    /// Pause between images during `POST /admin/backfill`, to keep load on R2 and MongoDB low
+    pub backfill_delay_ms: u64,
+    /// Let server-side fetches (`POST /mirror`) use plain http:// URLs
+    pub allow_http_outbound: bool,
+    pub mirror_timeout_secs: u64,
+    /// Remote fetches in flight at once, across all requests
+    pub max_outbound_concurrency: usize,
+    /// Remote fetches per host per minute (0 = unlimited)
+    pub outbound_host_rate: u32,
+    /// Consecutive S3 connectivity failures that open the storage breaker (0 = never opens)
+    pub s3_breaker_threshold: u32,
+    /// Seconds the breaker stays open; also the `Retry-After` on storage outages
+    pub s3_breaker_secs: u64,
+    /// Seconds an identical `POST /transfer` reuses the previous presign (0 = off, max 60, needs Redis)
+    pub presign_cache_secs: i64,
+    /// Seconds after completion before `GET /i/{id}` will serve an image (0 = immediately)
+    pub publish_delay_secs: i64,
+    /// Seconds a "no such image" result is remembered in Redis (0 = off)
+    pub miss_cache_secs: i64,
+    /// Largest object `?inline=1` embeds as a `data:` URI (0 = inlining off)
+    pub max_inline_bytes: u64,
+    /// `(start, end)` minutes past midnight UTC during which transfers are accepted; may wrap midnight
+    pub upload_window: Option<(u32, u32)>,
+    /// HEAD attempts before a completion reports the upload missing, and the pause between them
+    pub complete_head_attempts: u32,
+    pub complete_head_delay_ms: u64,
+    /// Retries of the image insert when completing an upload, before the object is recorded as an orphan
+    pub insert_retries: u32,
+    /// Serialize concurrent completions of one transfer with a Redis lock
+    pub complete_lock: bool,
+    /// Prefix object keys with a hash-derived shard so writes spread across key prefixes
+    pub hash_prefix: bool,
+    /// Reject completions whose uploaded size differs from the declared one instead of logging it
+    pub strict_size: bool,
+    /// Reject uploads whose file extension doesn't map to their content type
+    pub strict_extension: bool,
+    /// Hand out a per-image `deleteToken` on completion, accepted by `DELETE /i/{id}`
+    pub delete_tokens: bool,
+    pub name_dedup: bool,
+    /// Move completed uploads to a key named after their SHA-256, deduplicating identical files
+    pub content_addressed: bool,
+    pub verbose_keys: bool,
+    /// Add `iso_time` (RFC 3339, UTC) next to the epoch `t` in image payloads
+    pub iso_timestamps: bool,
+    /// Redirect target for unknown ids requested as images; takes precedence over the placeholder
+    pub not_found_image_url: Option<String>,
+    /// `Cross-Origin-Resource-Policy` sent with image redirects and placeholders
+    pub resource_policy: Option<String>,
+    pub not_found_placeholder: bool,
+    /// Send `X-Cache: HIT|MISS` on `GET /i/{id}` responses
+    pub cache_status_header: bool,
+    /// Leave the always-empty `d`/`P` out of new image documents
+    pub compact_docs: bool,
+    /// Require a CRC32C on `POST /transfer` and checksum server-side uploads too
+    pub crc32c_checksums: bool,
+    pub bool_ok: bool,
+    pub track_last_access: bool,
+    pub access_log: bool,
+    pub access_log_size_mb: u64,
+    pub max_description_len: usize,
+    pub max_tags: usize,
+    /// Longest tag value; keys have their own fixed bound
+    pub max_tag_len: usize,
+    /// Run one upload round trip against the backends and exit instead of serving (`--selftest`)
+    pub selftest: bool,
+}
+
+/// Upper bound on `ALLOWED_FORMATS` entries after de-duplication
+const MAX_ALLOWED_FORMATS: usize = 64;
+
+/// `*`, `type/*` or `type/subtype`, each part a MIME restricted name (RFC 6838)
+fn is_format_pattern(entry: &str) -> bool {
+    let is_name = |s: &str| {
+        !s.is_empty()
+            && s.len() <= 127
+            && s.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+
+    entry == "*"
+        || entry
+            .split_once('/')
+            .is_some_and(|(ty, sub)| is_name(ty) && (sub == "*" || is_name(sub)))
+}
+
+/// Comma-separated format patterns, lowercased and deduplicated; a malformed entry panics
+fn parse_formats(var: &str, raw: &str) -> Vec<String> {
+    let mut formats: Vec<String> = Vec::new();
+    for entry in raw
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+    {
+        assert!(
+            is_format_pattern(&entry),
+            "{var} entry '{entry}' is not type/subtype, type/* or *"
+        );
+        if !formats.contains(&entry) {
+            formats.push(entry);
+        }
+    }
+    formats
+}
+
+/// `RETENTION_POLICY=selector=secs,...`: selectors `anonymous`, `authenticated`, `key:{id}`,
+/// `type:{pattern}` or `*`, lifetimes in seconds or `never`. A malformed rule panics.
+fn parse_retention(raw: &str) -> Vec<(RetentionClass, Option<u64>)> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|rule| {
+            let invalid =
+                || panic!("RETENTION_POLICY rule '{rule}' must be selector=seconds or selector=never");
+            // Caller ids like `jwt:user` may hold most characters, but never `=`
+            let Some((selector, lifetime)) = rule.rsplit_once('=') else {
+                invalid()
+            };
+            let lifetime = match lifetime.trim() {
+                "never" => None,
+                secs => Some(
+                    secs.parse::<u64>()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .unwrap_or_else(|| invalid()),
+                ),
+            };
+            let selector = selector.trim();
+            let class = match selector {
+                "anonymous" => RetentionClass::Anonymous,
+                "authenticated" => RetentionClass::Authenticated,
+                "*" => RetentionClass::Any,
+                _ => {
+                    if let Some(id) = selector.strip_prefix("key:").filter(|id| !id.is_empty()) {
+                        RetentionClass::Caller(id.to_string())
+                    } else if let Some(pattern) = selector.strip_prefix("type:") {
+                        let pattern = pattern.to_lowercase();
+                        assert!(
+                            is_format_pattern(&pattern),
+                            "RETENTION_POLICY type '{pattern}' is not type/subtype, type/* or *"
+                        );
+                        RetentionClass::Format(pattern)
+                    } else {
+                        panic!(
+                            "RETENTION_POLICY selector '{selector}' must be anonymous, \
+                             authenticated, key:ID, type:PATTERN or *"
+                        )
+                    }
+                }
+            };
+            (class, lifetime)
+        })
+        .collect()
+}
+
+/// Parses `HH:MM-HH:MM` into minutes past midnight
+fn parse_window(raw: &str) -> Option<(u32, u32)> {
+    let minutes = |hm: &str| {
+        let (h, m) = hm.trim().split_once(':')?;
+        let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+        (h < 24 && m < 60).then_some(h * 60 + m)
+    };
+    let (start, end) = raw.split_once('-')?;
+    Some((minutes(start)?, minutes(end)?))
+}
+
+/// Reads an optional env var, falling back to `default` when unset or unparsable
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Reads an optional env var, `None` when unset or unparsable
+fn env_opt<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.trim().parse().ok())
+}
+
+/// A 256-bit AES key given as exactly 64 hex characters
+fn parse_aes_key(name: &str, hex_key: &str) -> [u8; 32] {
+    let key_bytes =
+        hex::decode(hex_key.trim()).unwrap_or_else(|_| panic!("{name} must be valid hex"));
+    key_bytes
+        .try_into()
+        .unwrap_or_else(|_| panic!("{name} must be exactly 32 bytes (64 hex characters)"))
+}
+
+/// Opt-in switches are only enabled by the literal value `true`
+fn env_flag(key: &str) -> bool {
+    env::var(key).is_ok_and(|v| v.trim() == "true")
 }
 
 impl Config {
@@ -22,43 +325,414 @@ impl Config {
             .parse::<u64>()
             .unwrap_or(99);
 
-        // Load encryption key from env, MUST be exactly 64 hex characters (32 bytes)
-        let hex_key = env::var("ENCRYPTION_KEY").expect("ENCRYPTION_KEY must be set in .env");
-        let key_bytes = hex::decode(&hex_key).expect("ENCRYPTION_KEY must be valid hex");
-        assert!(
-            key_bytes.len() == 32,
-            "ENCRYPTION_KEY must be exactly 32 bytes (64 hex characters)"
-        );
+        let obfuscate = env::var("OBFUSCATE").map_or(true, |v| v.trim() != "false");
+
+        // Load encryption key from env, MUST be exactly 64 hex characters (32 bytes).
+        // Plaintext deployments never encrypt, so they don't need one.
         let mut encryption_key = [0u8; 32];
-        encryption_key.copy_from_slice(&key_bytes);
+        if obfuscate {
+            let hex_key = env::var("ENCRYPTION_KEY").expect("ENCRYPTION_KEY must be set in .env");
+            encryption_key = parse_aes_key("ENCRYPTION_KEY", &hex_key);
+        }
 
         // Load allowed formats, default to common image types if missing
         let formats_str = env::var("ALLOWED_FORMATS").unwrap_or_else(|_| {
             "image/jpeg,image/png,image/webp,image/gif".into()
         });
         
-        let allowed_formats = formats_str
+        let allowed_formats = parse_formats("ALLOWED_FORMATS", &formats_str);
+        assert!(
+            !allowed_formats.is_empty(),
+            "ALLOWED_FORMATS must list at least one format"
+        );
+        assert!(
+            allowed_formats.len() <= MAX_ALLOWED_FORMATS,
+            "ALLOWED_FORMATS lists {} formats, max {MAX_ALLOWED_FORMATS}",
+            allowed_formats.len()
+        );
+        // Checked after the allowlist and wins over it, to carve exceptions out of `image/*` or `*`
+        let blocked_formats =
+            parse_formats("BLOCKED_FORMATS", &env::var("BLOCKED_FORMATS").unwrap_or_default());
+
+        // Names that can hijack or mislead when served from a public bucket; set empty to allow all
+        let blocked_filenames = env::var("BLOCKED_FILENAMES")
+            .unwrap_or_else(|_| {
+                "index.html,index.htm,robots.txt,sitemap.xml,crossdomain.xml,security.txt,.htaccess,.htpasswd".into()
+            })
             .split(',')
             .map(|s| s.trim().to_lowercase())
             .filter(|s| !s.is_empty())
             .collect();
 
+        // Reverse proxies / load balancers allowed to set X-Forwarded-For (CIDRs or bare IPs)
+        let trusted_proxies = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse::<IpNet>()
+                    .or_else(|_| s.parse::<std::net::IpAddr>().map(IpNet::from))
+                    .unwrap_or_else(|_| panic!("TRUSTED_PROXIES entry '{s}' is not an IP or CIDR"))
+            })
+            .collect();
+
+        let mut variant_widths: Vec<u32> = env::var("VARIANT_WIDTHS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse()
+                    .ok()
+                    .filter(|w| *w > 0)
+                    .unwrap_or_else(|| panic!("VARIANT_WIDTHS entry '{s}' is not a positive width"))
+            })
+            .collect();
+        variant_widths.sort_unstable();
+        variant_widths.dedup();
+
+        // One knob for every re-encode, with a per-operation override
+        let quality = |key: &str| {
+            env::var(key).ok().map(|s| {
+                s.trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|q| (1..=100).contains(q))
+                    .unwrap_or_else(|| panic!("{key} '{s}' must be a whole number from 1 to 100"))
+            })
+        };
+        let image_quality = quality("IMAGE_QUALITY").unwrap_or(80);
+        let variant_quality = quality("VARIANT_QUALITY").unwrap_or(image_quality);
+
+        let api_keys: Vec<(String, String)> = env::var("API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|entry| {
+                let (id, secret) = entry
+                    .split_once(':')
+                    .expect("API_KEYS entries must be id:secret");
+                assert!(
+                    !id.is_empty() && !secret.is_empty(),
+                    "API_KEYS entries must be id:secret"
+                );
+                (id.to_string(), secret.to_string())
+            })
+            .collect();
+
+        let key_concurrency_overrides = env::var("KEY_CONCURRENCY_OVERRIDES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once(':')
+                    .and_then(|(id, n)| Some((id.trim().to_string(), n.trim().parse().ok()?)))
+                    .filter(|(id, _)| !id.is_empty())
+                    .unwrap_or_else(|| {
+                        panic!("KEY_CONCURRENCY_OVERRIDES entry '{entry}' must be id:limit")
+                    })
+            })
+            .collect();
+
+        let tenant_encryption_keys: HashMap<String, [u8; 32]> = env::var("TENANT_ENCRYPTION_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|entry| {
+                let (id, hex_key) = entry
+                    .split_once(':')
+                    .filter(|(id, _)| !id.trim().is_empty())
+                    .unwrap_or_else(|| {
+                        panic!("TENANT_ENCRYPTION_KEYS entry '{entry}' must be id:hexkey")
+                    });
+                let id = id.trim();
+                // The id travels in a one-byte length prefix of the payload
+                assert!(
+                    id.len() <= usize::from(u8::MAX),
+                    "TENANT_ENCRYPTION_KEYS id '{id}' is longer than 255 bytes"
+                );
+                assert!(
+                    api_keys.iter().any(|(key_id, _)| key_id == id),
+                    "TENANT_ENCRYPTION_KEYS id '{id}' is not an API_KEYS id"
+                );
+                let key = parse_aes_key(&format!("TENANT_ENCRYPTION_KEYS key for '{id}'"), hex_key);
+                (id.to_string(), key)
+            })
+            .collect();
+        let name_dedup = env_flag("NAME_DEDUP");
+        let content_addressed = env_flag("CONTENT_ADDRESSED");
+        // Name dedup matches the key handed out at transfer time, which completion then replaces
+        assert!(
+            !(name_dedup && content_addressed),
+            "NAME_DEDUP and CONTENT_ADDRESSED can't both be enabled"
+        );
+        let payload_versioned = env_flag("PAYLOAD_VERSIONED");
+        // Clients can only tell which key a payload used from the versioned prefix
+        assert!(
+            !obfuscate || tenant_encryption_keys.is_empty() || payload_versioned,
+            "TENANT_ENCRYPTION_KEYS requires PAYLOAD_VERSIONED=true"
+        );
+
+        let object_metadata = env::var("OBJECT_METADATA")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once(':')
+                    .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+                    .filter(|(name, value)| {
+                        !name.is_empty()
+                            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                            && value.is_ascii()
+                    })
+                    .unwrap_or_else(|| {
+                        panic!("OBJECT_METADATA entry '{entry}' must be name:value (ASCII, name of [A-Za-z0-9-])")
+                    })
+            })
+            .collect();
+
+        // Tiered storage: old originals move to a second bucket with its own public domain
+        let r2_cold_bucket = env_opt::<String>("R2_COLD_BUCKET").filter(|s| !s.is_empty());
+        let r2_cold_public_domain = env::var("R2_COLD_PUBLIC_DOMAIN")
+            .map(|s| s.trim().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        let cold_after_days: u32 = env_or("COLD_AFTER_DAYS", 30);
+        if let Some(cold) = &r2_cold_bucket {
+            assert!(
+                env::var("R2_BUCKET").ok().as_deref() != Some(cold.as_str()),
+                "R2_COLD_BUCKET must differ from R2_BUCKET"
+            );
+            assert!(
+                !r2_cold_public_domain.is_empty(),
+                "R2_COLD_PUBLIC_DOMAIN is required with R2_COLD_BUCKET"
+            );
+            assert!(cold_after_days > 0, "COLD_AFTER_DAYS must be at least 1");
+        }
+        // The handler deadline and the completion lock's expiry both come from this
+        let request_timeout_secs: u64 = env_or("REQUEST_TIMEOUT_SECS", 30);
+        assert!(request_timeout_secs > 0, "REQUEST_TIMEOUT_SECS must be at least 1");
+
         Self {
             r2_endpoint: env::var("R2_ENDPOINT").expect("R2_ENDPOINT required"),
             r2_bucket: env::var("R2_BUCKET").expect("R2_BUCKET required"),
             r2_access_key: env::var("R2_ACCESS_KEY").expect("R2_ACCESS_KEY required"),
             r2_secret_key: env::var("R2_SECRET_KEY").expect("R2_SECRET_KEY required"),
             r2_public_domain: env::var("R2_PUBLIC_DOMAIN").expect("R2_PUBLIC_DOMAIN required"),
+            r2_cdn_domain: env::var("R2_CDN_DOMAIN")
+                .ok()
+                .map(|s| s.trim().trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty()),
+            r2_cold_bucket,
+            r2_cold_public_domain,
+            cold_after_days,
+            url_template: env::var("URL_TEMPLATE")
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .inspect(|s| {
+                    assert!(s.contains("{key}"), "URL_TEMPLATE '{s}' must contain {{key}}");
+                }),
+            s3_region: env::var("S3_REGION")
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| "auto".into()),
+            s3_connect_timeout_ms: env_opt("S3_CONNECT_TIMEOUT_MS"),
+            s3_operation_timeout_ms: env_opt("S3_OPERATION_TIMEOUT_MS"),
             mongo_uri: env::var("MONGO_URI").expect("MONGO_URI required"),
-            redis_url: env::var("REDIS_URL").expect("REDIS_URL required"),
+            mongo_max_pool_size: env_opt::<u32>("MONGO_MAX_POOL_SIZE").filter(|&n| n > 0),
+            mongo_connect_timeout_ms: env_opt("MONGO_CONNECT_TIMEOUT_MS"),
+            mongo_server_selection_timeout_ms: env_opt("MONGO_SERVER_SELECTION_TIMEOUT_MS"),
+            mongo_shard_count: {
+                let count: usize = env_or("MONGO_SHARD_COUNT", 1);
+                assert!((1..=256).contains(&count), "MONGO_SHARD_COUNT must be from 1 to 256");
+                count
+            },
+            redis_url: env::var("CACHE_ENABLED")
+                .map_or(true, |v| v.trim() != "false")
+                .then(|| env::var("REDIS_URL").expect("REDIS_URL required")),
             port: env::var("PORT")
                 .unwrap_or_else(|_| "3000".into())
                 .parse()
                 .unwrap_or(3000),
+            route_prefix: env::var("ROUTE_PREFIX")
+                .map(|p| {
+                    let p = p.trim().trim_matches('/');
+                    if p.is_empty() {
+                        String::new()
+                    } else {
+                        format!("/{p}")
+                    }
+                })
+                .unwrap_or_default(),
             max_size: max_size_mb * 1024 * 1024,
             max_size_mb,
             encryption_key,
+            tenant_encryption_keys,
+            obfuscate,
+            payload_versioned,
+            compress_payload: env_flag("COMPRESS_PAYLOAD"),
             allowed_formats,
+            blocked_formats,
+            retention_policy: parse_retention(&env::var("RETENTION_POLICY").unwrap_or_default()),
+            extract_meta: env_flag("EXTRACT_META"),
+            request_timeout_secs,
+            http_keep_alive_secs: env_opt("HTTP_KEEP_ALIVE_SECS"),
+            http2: env_flag("HTTP2"),
+            compress_min_bytes: env_or("COMPRESS_MIN_BYTES", 1024),
+            force_https: env_flag("FORCE_HTTPS"),
+            hsts_max_age_secs: env_or("HSTS_MAX_AGE", 31_536_000),
+            redis_key_prefix: env::var("REDIS_KEY_PREFIX").unwrap_or_default(),
+            pending_namespace: env::var("PENDING_NAMESPACE")
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| "pending".into()),
+            infer_content_type: env_flag("INFER_CONTENT_TYPE"),
+            api_keys,
+            // Env files usually can't hold newlines, so `\n` escapes are accepted in the PEM
+            jwt_public_key: env::var("JWT_PUBLIC_KEY")
+                .ok()
+                .map(|pem| pem.trim().replace("\\n", "\n"))
+                .filter(|pem| !pem.is_empty()),
+            jwt_jwks_url: env_opt::<String>("JWT_JWKS_URL").filter(|s| !s.is_empty()),
+            jwt_issuer: env_opt::<String>("JWT_ISSUER").filter(|s| !s.is_empty()),
+            jwt_audience: env_opt::<String>("JWT_AUDIENCE").filter(|s| !s.is_empty()),
+            require_transfer_auth: env_flag("REQUIRE_TRANSFER_AUTH"),
+            key_concurrency: env_or("KEY_CONCURRENCY", 0),
+            key_concurrency_overrides,
+            blocked_filenames,
+            lowercase_keys: env_flag("LOWERCASE_KEYS"),
+            safe_keys: env_flag("SAFE_KEYS"),
+            name_nfkc: env_flag("NAME_NFKC"),
+            trusted_proxies,
+            variant_widths,
+            variant_quality,
+            webp_negotiation: env_flag("WEBP_NEGOTIATION"),
+            dominant_color: env_flag("DOMINANT_COLOR"),
+            phash: env_flag("PHASH"),
+            max_concurrent_processing: env_or("MAX_CONCURRENT_PROCESSING", 2).max(1),
+            max_decode_bytes: env_or::<u64>("MAX_DECODE_MB", 256).max(1) * 1024 * 1024,
+            min_width: env_opt::<u32>("MIN_WIDTH").filter(|&w| w > 0),
+            min_height: env_opt::<u32>("MIN_HEIGHT").filter(|&h| h > 0),
+            aspect_ratio: env_opt::<String>("ASPECT_RATIO")
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    s.split_once(':')
+                        .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+                        .filter(|&(w, h): &(u32, u32)| w > 0 && h > 0)
+                        .unwrap_or_else(|| panic!("ASPECT_RATIO '{s}' must be W:H, e.g. 16:9"))
+                }),
+            max_decode_ms: env_or("MAX_DECODE_MS", 10_000),
+            clamav_addr: env::var("CLAMAV_ADDR").ok().filter(|s| !s.trim().is_empty()),
+            clamav_timeout_secs: env_or("CLAMAV_TIMEOUT_SECS", 20),
+            clamav_fail_open: env_flag("CLAMAV_FAIL_OPEN"),
+            max_total_images: Some(env_or("MAX_TOTAL_IMAGES", 0)).filter(|&n| n > 0),
+            object_cache_control: env_opt::<String>("OBJECT_CACHE_CONTROL").filter(|s| !s.is_empty()),
+            object_metadata,
+            backfill_delay_ms: env_or("BACKFILL_DELAY_MS", 200),
+            allow_http_outbound: env_flag("ALLOW_HTTP_OUTBOUND"),
+            mirror_timeout_secs: env_or("MIRROR_TIMEOUT_SECS", 15),
+            max_outbound_concurrency: env_or("MAX_OUTBOUND_CONCURRENCY", 4).max(1),
+            outbound_host_rate: env_or("OUTBOUND_HOST_RATE", 10),
+            s3_breaker_threshold: env_or("S3_BREAKER_THRESHOLD", 5),
+            s3_breaker_secs: env_or::<u64>("S3_BREAKER_SECS", 30).max(1),
+            presign_cache_secs: env_or::<i64>("PRESIGN_CACHE_SECS", 0).clamp(0, 60),
+            publish_delay_secs: env_or::<i64>("PUBLISH_DELAY_SECS", 0).max(0),
+            miss_cache_secs: env_or::<i64>("MISS_CACHE_SECS", 5).max(0),
+            max_inline_bytes: env_or("MAX_INLINE_BYTES", 16_384),
+            upload_window: env::var("UPLOAD_WINDOW")
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    parse_window(&s).unwrap_or_else(|| {
+                        panic!("UPLOAD_WINDOW '{s}' must be HH:MM-HH:MM (UTC)")
+                    })
+                }),
+            complete_head_attempts: env_or("COMPLETE_HEAD_ATTEMPTS", 3).max(1),
+            complete_head_delay_ms: env_or("COMPLETE_HEAD_DELAY_MS", 250),
+            insert_retries: env_or::<u32>("INSERT_RETRIES", 2).min(10),
+            complete_lock: env::var("COMPLETE_LOCK").map_or(true, |v| v.trim() != "false"),
+            hash_prefix: env_flag("HASH_PREFIX"),
+            strict_size: env_flag("STRICT_SIZE"),
+            strict_extension: env_flag("STRICT_EXTENSION"),
+            delete_tokens: env_flag("DELETE_TOKENS"),
+            name_dedup,
+            content_addressed,
+            verbose_keys: env_flag("VERBOSE_KEYS"),
+            iso_timestamps: env_flag("ISO_TIMESTAMPS"),
+            not_found_image_url: env::var("NOT_FOUND_IMAGE_URL")
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            not_found_placeholder: env_flag("NOT_FOUND_PLACEHOLDER"),
+            cache_status_header: env_flag("CACHE_STATUS_HEADER"),
+            compact_docs: env_flag("COMPACT_DOCS"),
+            crc32c_checksums: env_flag("CRC32C_CHECKSUMS"),
+            resource_policy: env_opt::<String>("CROSS_ORIGIN_RESOURCE_POLICY").map(|s| {
+                let s = s.to_ascii_lowercase();
+                if !matches!(s.as_str(), "same-origin" | "same-site" | "cross-origin") {
+                    panic!("CROSS_ORIGIN_RESOURCE_POLICY '{s}' must be same-origin, same-site or cross-origin");
+                }
+                s
+            }),
+            bool_ok: env_flag("BOOL_OK"),
+            track_last_access: env_flag("TRACK_LAST_ACCESS"),
+            access_log: env_flag("ACCESS_LOG"),
+            access_log_size_mb: env_or::<u64>("ACCESS_LOG_SIZE_MB", 100).max(1),
+            max_description_len: env_or("MAX_DESCRIPTION_LEN", 1000),
+            max_tags: env_or("MAX_TAGS", 20),
+            max_tag_len: env_or("MAX_TAG_LEN", 256),
+            selftest: env_flag("SELFTEST") || env::args().skip(1).any(|a| a == "--selftest"),
+        }
+    }
+
+    /// Names of the image collections: `i`, or `i_00`, `i_01`, ... with `MONGO_SHARD_COUNT` > 1
+    pub fn image_collections(&self) -> Vec<String> {
+        if self.mongo_shard_count <= 1 {
+            return vec!["i".into()];
+        }
+        (0..self.mongo_shard_count).map(|n| format!("i_{n:02x}")).collect()
+    }
+
+    /// Builds every Redis key as `{prefix}{namespace}:{id}` so environments can share one instance
+    pub fn redis_key(&self, namespace: &str, id: &str) -> String {
+        format!("{}{namespace}:{id}", self.redis_key_prefix)
+    }
+
+    /// In-flight cap for one API key; 0 means unlimited
+    pub fn key_concurrency_for(&self, key_id: &str) -> usize {
+        self.key_concurrency_overrides
+            .get(key_id)
+            .copied()
+            .unwrap_or(self.key_concurrency)
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The env defaults plus placeholder connection settings; tests set the fields they exercise
+    pub fn for_tests() -> Self {
+        for (key, value) in [
+            ("OBFUSCATE", "false"),
+            ("CACHE_ENABLED", "false"),
+            ("R2_ENDPOINT", "http://127.0.0.1:9000"),
+            ("R2_BUCKET", "test"),
+            ("R2_ACCESS_KEY", "test"),
+            ("R2_SECRET_KEY", "test"),
+            ("R2_PUBLIC_DOMAIN", "https://cdn.example.com"),
+            ("MONGO_URI", "mongodb://127.0.0.1:27017"),
+        ] {
+            env::set_var(key, value);
         }
+        Self::from_env()
     }
 }
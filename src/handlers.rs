@@ -1,33 +1,332 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
 use aes_gcm::aead::{Aead, KeyInit};
 use aes_gcm::{Aes256Gcm, Nonce};
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::head_object::{HeadObjectError, HeadObjectOutput};
 use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    ChecksumMode, CompletedMultipartUpload, CompletedPart, Delete, MetadataDirective,
+    ObjectIdentifier,
+};
 use aws_sdk_s3::Client as S3Client;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use fred::prelude::*;
+use futures_util::{stream, TryStreamExt};
 use mongodb::Collection;
 use rand::rngs::OsRng;
 use rand::Rng;
 use serde_json::json;
-use std::time::Duration;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::IntoFuture;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, OnceCell, OwnedSemaphorePermit, Semaphore};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::config::Config;
-use crate::models::{AppError, ImageResponsePayload, ObfuscatedResponse, PendingTransfer, TransferRequest, TransferResponse};
+use crate::auth;
+use crate::av::{self, Verdict};
+use crate::config::{Config, RetentionClass};
+use crate::imaging;
+use crate::jwt;
+use crate::metadata;
+use crate::mime;
+use crate::models::{
+    AppError, BulkDeleteRequest, CacheWarmRequest, ImageMeta, MirrorRequest, ImageQuery, ImageResponsePayload, ImageVariant,
+    ListItem, ListQuery, ObfuscatedResponse, OkFlag,
+    PendingTransfer, PlainResponse, RenameRequest, SimilarQuery, StaleQuery, TransferEvent, TransferRequest, TransferResponse,
+    UploadQuery, UsageQuery,
+};
+use crate::net;
+use crate::remote;
+use crate::svg;
+
+/// EXIF lives in the first APP1 segment for JPEG (max 64KB); 256KB also covers most PNG/WebP layouts
+const META_SCAN_BYTES: u64 = 256 * 1024;
+
+/// Enough of the file for `imaging::sniff` to recognise every raster format
+const SNIFF_BYTES: u64 = 64;
+
+/// Uploads with this stored type are sanitized before they're published
+const SVG_TYPE: &str = "image/svg+xml";
+
+/// How long a catalog size snapshot is trusted before re-counting
+const IMAGE_COUNT_TTL_SECS: i64 = 30;
+
+/// Upper bound on ids per bulk delete (S3 `DeleteObjects` itself caps at 1000)
+const MAX_BULK_DELETE: usize = 100;
+
+/// Upper bound on ids per cache warm, each of which is a Redis write
+const MAX_CACHE_WARM: usize = 100;
+
+const MAX_TAG_KEY_LEN: usize = 64;
+
+/// Part size for `PUT /upload` multipart writes, and so the most of one body held in memory
+/// (S3 wants at least 5 MiB for every part but the last)
+const UPLOAD_PART_BYTES: usize = 8 * 1024 * 1024;
+
+/// Bounds on an image's `acl`: entries, and the length of one caller id
+const MAX_ACL_ENTRIES: usize = 32;
+const MAX_ACL_ID_LEN: usize = 128;
+
+/// Longest `expires_in_secs` a transfer may ask for (10 years)
+const MAX_EXPIRES_IN_SECS: u64 = 10 * 365 * 86400;
+
+/// Images removed per query by the expiry sweep
+const EXPIRY_SWEEP_BATCH: i64 = 100;
+
+/// Relative slack allowed by `ASPECT_RATIO` (1%)
+const ASPECT_TOLERANCE: f64 = 0.01;
+
+/// Originals moved per query by the cold-storage migration
+const COLD_MIGRATION_BATCH: i64 = 100;
+
+/// Lifetime of a pending transfer, matching the presigned URL expiry
+const PENDING_TTL_SECS: i64 = 300;
+
+/// Lifetime of a cached image payload
+const CACHE_TTL_SECS: i64 = 86400;
+/// How long a tenant's usage totals are reused before aggregating again
+const USAGE_CACHE_SECS: i64 = 60;
+
+/// Minimum gap between two `la` (last-accessed) writes for the same image
+const ACCESS_WRITE_INTERVAL_SECS: i64 = 3600;
+
+/// 1x1 transparent PNG served for unknown ids with `NOT_FOUND_PLACEHOLDER=true`
+const TRANSPARENT_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x00, 0x02, 0x00,
+    0x00, 0x05, 0x00, 0x01, 0xe9, 0xfa, 0xdc, 0xd8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+    0xae, 0x42, 0x60, 0x82,
+];
+
+/// User-Agent strings are truncated to this many chars in the access log
+const MAX_LOGGED_UA_LEN: usize = 256;
+
+/// Most recent hashed images compared by `GET /i/{id}/similar`
+const MAX_SIMILAR_SCAN: i64 = 5000;
+const MAX_SIMILAR_RESULTS: usize = 20;
+/// Default / upper Hamming distance for `GET /i/{id}/similar` (out of 64 bits)
+const DEFAULT_SIMILAR_DISTANCE: u32 = 10;
+const MAX_SIMILAR_DISTANCE: u32 = 24;
+
+/// Fresh ids tried by `POST /i/{id}/rotate-id` before giving up on collisions
+const ROTATE_ID_ATTEMPTS: usize = 5;
+
+/// Images per backfill batch; progress is checkpointed after each batch
+const BACKFILL_BATCH: i64 = 50;
+const BACKFILL_JOB: &str = "backfill";
+
+/// How often a completion waiting on another one's lock checks whether it was released
+const COMPLETE_LOCK_POLL_MS: u64 = 100;
+/// Longest a completion waits on another one's lock before answering `409`
+const COMPLETE_LOCK_WAIT_SECS: u64 = 10;
+/// Deletes the completion lock only while it still holds the caller's token, so a holder that
+/// outlived the TTL can't release a lock another completion has taken since
+const COMPLETE_UNLOCK_SCRIPT: &str =
+    "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+/// Pause before the first retry of a failed image insert; doubled for each further retry
+const INSERT_RETRY_DELAY_MS: u64 = 200;
+
+/// Fields `GET /i?fields=` can select: terse name, `VERBOSE_KEYS` name, and the stored fields
+/// needed to build it. `P` is deliberately absent.
+const LIST_FIELDS: &[(&str, &str, &[&str])] = &[
+    ("url", "url", &["f", "w", "h", "b"]),
+    ("cdn_url", "cdn_url", &["f", "b"]),
+    ("origin_url", "origin_url", &["f", "b"]),
+    ("f", "filename", &["f"]),
+    ("s", "size", &["s"]),
+    ("t", "timestamp", &["t"]),
+    ("iso_time", "iso_time", &["t"]),
+    ("d", "description", &["d"]),
+    ("desc", "caption", &["desc"]),
+    ("orig_name", "original_name", &["orig_name"]),
+    ("w", "width", &["w"]),
+    ("h", "height", &["h"]),
+    ("color", "color", &["color"]),
+    ("meta", "meta", &["meta"]),
+    ("variants", "variants", &["variants"]),
+    ("tags", "tags", &["tags"]),
+];
+
+/// Most entries returned by `GET /admin/pending`
+const MAX_PENDING_LIST: usize = 100;
+
+/// Page size bounds for `GET /i`
+const DEFAULT_LIST_LIMIT: u32 = 50;
+const MAX_LIST_LIMIT: u32 = 200;
 
 pub struct AppState {
     pub config: Config,
     pub s3: S3Client,
-    pub db: Collection<mongodb::bson::Document>,
-    pub redis: RedisClient,
+    /// Image collections in `Config::image_collections` order; a single `i` unless sharded
+    pub images: Vec<Collection<mongodb::bson::Document>>,
+    /// `None` when `CACHE_ENABLED=false`
+    pub redis: Option<RedisClient>,
+    /// Pending transfers when running without Redis (TTL-indexed on `exp`)
+    pub pending: Collection<mongodb::bson::Document>,
+    /// Capped audit collection written by `get_image` with `ACCESS_LOG=true`
+    pub access_log: Collection<mongodb::bson::Document>,
+    pub events: broadcast::Sender<TransferEvent>,
+    /// Bounds concurrent decode/encode work across all requests
+    pub processing: Arc<Semaphore>,
+    /// Bounds concurrent remote fetches, for `MAX_OUTBOUND_CONCURRENCY`
+    pub outbound: Semaphore,
+    /// Remote fetches per host in the current minute (window start, count), for `OUTBOUND_HOST_RATE`
+    pub outbound_hosts: Mutex<HashMap<String, (i64, u32)>>,
+    /// `estimated_document_count` snapshot and the unix second it was taken
+    pub image_count: AtomicU64,
+    pub image_count_at: AtomicI64,
+    /// Consecutive S3 connectivity failures, reset by any answer from S3
+    pub s3_failures: AtomicU32,
+    /// Unix second until which S3 calls are refused without being attempted
+    pub s3_open_until: AtomicI64,
+    /// In-flight gated requests per API key, for `KEY_CONCURRENCY`
+    pub key_in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    /// In-progress MongoDB lookups by image id, shared by concurrent cache misses
+    pub lookups: Mutex<HashMap<String, Arc<OnceCell<Option<mongodb::bson::Document>>>>>,
+    /// Background job checkpoints (`imgdock.jobs`)
+    pub jobs: Collection<mongodb::bson::Document>,
+    pub backfill_running: AtomicBool,
+    /// Stored objects whose image document could not be written (`imgdock.orphans`)
+    pub orphans: Collection<mongodb::bson::Document>,
+    /// Bearer token verification; `None` unless `JWT_PUBLIC_KEY` or `JWT_JWKS_URL` is set
+    pub jwt: Option<jwt::Verifier>,
 }
 
-/// Returns (YYYYMMDD date folder, unix timestamp seconds)
-fn now_parts() -> (String, i64) {
-    let secs = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+impl AppState {
+    /// Index into `images` of the collection holding `id`. With `MONGO_SHARD_COUNT` > 1 it is
+    /// picked by a hash of the id, so every instance routes an id the same way.
+    pub fn shard_of(&self, id: &str) -> usize {
+        if self.images.len() == 1 {
+            return 0;
+        }
+        let digest = Sha256::digest(id.as_bytes());
+        usize::from(u16::from_be_bytes([digest[0], digest[1]])) % self.images.len()
+    }
+
+    /// The collection holding image `id`
+    pub fn images_for(&self, id: &str) -> &Collection<mongodb::bson::Document> {
+        &self.images[self.shard_of(id)]
+    }
+}
+
+/// Orders two documents by one field the way MongoDB sorts it: missing first, then strings
+/// bytewise or numbers by value
+fn compare_field(a: &mongodb::bson::Document, b: &mongodb::bson::Document, field: &str) -> std::cmp::Ordering {
+    match (a.get(field), b.get(field)) {
+        (Some(mongodb::bson::Bson::String(x)), Some(mongodb::bson::Bson::String(y))) => x.cmp(y),
+        (Some(x), Some(y)) => x.as_i64().cmp(&y.as_i64()),
+        (x, y) => x.is_some().cmp(&y.is_some()),
+    }
+}
+
+/// Runs one query on every image collection and merges the results in `sort` order (a single
+/// `(field, 1 | -1)`), keeping the first `limit`. The sort field must survive `projection`.
+async fn find_images(
+    state: &AppState,
+    filter: mongodb::bson::Document,
+    projection: Option<mongodb::bson::Document>,
+    sort: (&str, i32),
+    limit: i64,
+) -> Result<Vec<mongodb::bson::Document>, AppError> {
+    let (field, order) = sort;
+    let batches = futures_util::future::try_join_all(state.images.iter().map(|images| {
+        let mut find = images
+            .find(filter.clone())
+            .sort(mongodb::bson::doc! { field: order })
+            .limit(limit);
+        if let Some(projection) = &projection {
+            find = find.projection(projection.clone());
+        }
+        async move { find.await?.try_collect::<Vec<_>>().await }
+    }))
+    .await
+    .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+
+    let mut docs: Vec<mongodb::bson::Document> = batches.into_iter().flatten().collect();
+    if state.images.len() > 1 {
+        docs.sort_by(|a, b| {
+            let ordering = compare_field(a, b, field);
+            if order < 0 {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        docs.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+    }
+    Ok(docs)
+}
+
+/// First image matching `filter` in any collection, for lookups by something other than the id
+async fn find_one_image(
+    state: &AppState,
+    filter: mongodb::bson::Document,
+    projection: mongodb::bson::Document,
+) -> Result<Option<mongodb::bson::Document>, AppError> {
+    for images in &state.images {
+        let found = images
+            .find_one(filter.clone())
+            .projection(projection.clone())
+            .await
+            .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+/// Holds one of an API key's `KEY_CONCURRENCY` slots; released on drop
+struct KeySlot {
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    key_id: String,
+}
+
+impl Drop for KeySlot {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(n) = in_flight.get_mut(&self.key_id) {
+            *n -= 1;
+            if *n == 0 {
+                in_flight.remove(&self.key_id);
+            }
+        }
+    }
+}
+
+/// Claims an in-flight slot for `key_id`, or 429 when the key is already at its limit
+fn claim_key_slot(state: &AppState, key_id: &str) -> Result<Option<KeySlot>, AppError> {
+    let limit = state.config.key_concurrency_for(key_id);
+    if limit == 0 {
+        return Ok(None);
+    }
+
+    let mut in_flight = state.key_in_flight.lock().unwrap_or_else(|e| e.into_inner());
+    let n = in_flight.entry(key_id.to_string()).or_insert(0);
+    if *n >= limit {
+        return Err(AppError::TooManyRequests(format!(
+            "API key already has {limit} requests in flight"
+        )));
+    }
+    *n += 1;
+
+    Ok(Some(KeySlot {
+        in_flight: Arc::clone(&state.key_in_flight),
+        key_id: key_id.to_string(),
+    }))
+}
 
-    let days = secs / 86400;
+/// Days since the unix epoch as (year, month, day), Hinnant's `civil_from_days`
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
     let z = days + 719_468;
     let era = z / 146_097;
     let doe = z - era * 146_097;
@@ -38,11 +337,42 @@ fn now_parts() -> (String, i64) {
     let d = doy - (153 * mp + 2) / 5 + 1;
     let m = if mp < 10 { mp + 3 } else { mp - 9 };
     let y = if m <= 2 { y + 1 } else { y };
+    (y, m, d)
+}
+
+/// Returns (YYYYMMDD date folder, unix timestamp seconds)
+fn now_parts() -> (String, i64) {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let (y, m, d) = civil_from_days(secs / 86400);
 
     #[allow(clippy::cast_possible_wrap)]
     (format!("{y}{m:02}{d:02}"), secs as i64)
 }
 
+/// Unix seconds as an RFC 3339 UTC timestamp (`2026-02-22T10:40:00Z`)
+fn rfc3339(ts: i64) -> String {
+    let secs = u64::try_from(ts).unwrap_or(0);
+    let (y, m, d) = civil_from_days(secs / 86400);
+    let rem = secs % 86400;
+    format!(
+        "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// 256-bit random delete token, hex-encoded
+fn gen_delete_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::RngCore::fill_bytes(&mut OsRng, &mut bytes);
+    hex::encode(bytes)
+}
+
 fn gen_id() -> String {
     const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
     let mut rng = rand::thread_rng();
@@ -51,268 +381,4485 @@ fn gen_id() -> String {
         .collect()
 }
 
-/// Encrypts JSON payload using AES-256-GCM. Returns hex-encoded "iv + ciphertext + `auth_tag`"
-fn encrypt_payload(json: &str, key: &[u8; 32]) -> Result<String, AppError> {
+/// Wire-format version 1: AES-256-GCM with a 12-byte nonce
+const PAYLOAD_V1: u8 = 1;
+/// Wire-format version 2: as version 1, under a `TENANT_ENCRYPTION_KEYS` key whose id follows
+/// the version byte as one length byte and the id's UTF-8 bytes
+const PAYLOAD_V2: u8 = 2;
+
+/// Encrypts JSON payload using AES-256-GCM. Returns hex-encoded "iv + ciphertext + `auth_tag`",
+/// prefixed with a format version byte when `versioned` (`PAYLOAD_VERSIONED=true`). A tenant
+/// `key_id` selects version 2; config validation guarantees it only comes with `versioned`.
+fn encrypt_payload(
+    plaintext: &[u8],
+    key: &[u8; 32],
+    key_id: Option<&str>,
+    versioned: bool,
+) -> Result<String, AppError> {
     let cipher = Aes256Gcm::new(key.into());
     let mut nonce_bytes = [0u8; 12];
     rand::RngCore::fill_bytes(&mut OsRng, &mut nonce_bytes);
 
     let ciphertext = cipher
-        .encrypt(Nonce::from_slice(&nonce_bytes), json.as_bytes())
+        .encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
         .map_err(|_| AppError::Internal("Encryption failure".into()))?;
 
-    // Prepend 12-byte IV/nonce to ciphertext
-    let mut final_payload = nonce_bytes.to_vec();
+    // [version] + [key id length + key id] + 12-byte IV/nonce + ciphertext
+    let id_len = key_id.map_or(0, |id| 1 + id.len());
+    let mut final_payload = Vec::with_capacity(1 + id_len + nonce_bytes.len() + ciphertext.len());
+    match key_id {
+        Some(id) => {
+            let len = u8::try_from(id.len())
+                .map_err(|_| AppError::Internal("Encryption key id too long".into()))?;
+            final_payload.push(PAYLOAD_V2);
+            final_payload.push(len);
+            final_payload.extend_from_slice(id.as_bytes());
+        }
+        None if versioned => final_payload.push(PAYLOAD_V1),
+        None => {}
+    }
+    final_payload.extend_from_slice(&nonce_bytes);
     final_payload.extend_from_slice(&ciphertext);
 
     Ok(hex::encode(final_payload))
 }
 
-// POST /transfer
-pub async fn create_transfer(
-    state: web::Data<AppState>,
-    body: web::Json<TransferRequest>,
-) -> Result<HttpResponse, AppError> {
-    if body.name.is_empty() {
-        return Err(AppError::BadRequest("Name cannot be empty".into()));
+/// The key a `GET /i/{id}` payload is encrypted with: the caller's `TENANT_ENCRYPTION_KEYS`
+/// entry when it presents an API key that has one, otherwise the global `ENCRYPTION_KEY`.
+/// An `X-Api-Key` that doesn't match `API_KEYS` is rejected rather than silently ignored.
+fn payload_key<'a>(
+    config: &'a Config,
+    req: &HttpRequest,
+) -> Result<(Option<&'a str>, &'a [u8; 32]), AppError> {
+    if config.tenant_encryption_keys.is_empty()
+        || !req.headers().contains_key(auth::API_KEY_HEADER)
+    {
+        return Ok((None, &config.encryption_key));
     }
+    let key_id = auth::require_api_key(req, config)?;
+    Ok(config
+        .tenant_encryption_keys
+        .get_key_value(&key_id)
+        .map_or((None, &config.encryption_key), |(id, key)| (Some(id.as_str()), key)))
+}
 
-    let content_type = body.content_type.to_lowercase();
-    if !state.config.allowed_formats.contains(&content_type)
-        && !state.config.allowed_formats.contains(&"*".to_string())
-    {
+/// Gzips the payload JSON for `COMPRESS_PAYLOAD`. Compressing first is what makes it work:
+/// ciphertext doesn't compress.
+fn gzip(json: &str) -> Result<Vec<u8>, AppError> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(json.len() / 2), Compression::default());
+    encoder
+        .write_all(json.as_bytes())
+        .and_then(|()| encoder.finish())
+        .map_err(|e| AppError::Internal(format!("Compression failure: {e}")))
+}
+
+/// Checks client description and tags against the configured size limits and Mongo field-name rules.
+/// Every path that writes client metadata goes through here.
+fn validate_metadata(
+    config: &Config,
+    description: &str,
+    tags: &HashMap<String, String>,
+) -> Result<(), AppError> {
+    if description.chars().count() > config.max_description_len {
         return Err(AppError::BadRequest(format!(
-            "Unsupported file format. Allowed: {}",
-            state.config.allowed_formats.join(", ")
+            "Description exceeds {} chars",
+            config.max_description_len
         )));
     }
-    if body.size > state.config.max_size {
-        return Err(AppError::LargePayload(format!(
-            "Max {}MB",
-            state.config.max_size_mb
-        )));
+    if tags.len() > config.max_tags {
+        return Err(AppError::BadRequest(format!("Max {} tags", config.max_tags)));
     }
+    for (k, v) in tags {
+        // Tag keys become sub-document field names, so `.` and a leading `$` are not allowed
+        if k.is_empty() || k.len() > MAX_TAG_KEY_LEN || k.contains('.') || k.starts_with('$') {
+            return Err(AppError::BadRequest(format!(
+                "Tag keys must be 1-{MAX_TAG_KEY_LEN} chars without '.' or a leading '$'"
+            )));
+        }
+        if v.chars().count() > config.max_tag_len {
+            return Err(AppError::BadRequest(format!(
+                "Tag '{k}' exceeds {} chars",
+                config.max_tag_len
+            )));
+        }
+    }
+    Ok(())
+}
 
-    let id = gen_id();
-    let (date, _) = now_parts();
-    let key = format!("{date}/{}", body.name);
-
-    log::info!("Transfer: {id} → {key}");
-
-    let presign_config = PresigningConfig::builder()
-        .expires_in(Duration::from_secs(300))
-        .build()
-        .map_err(|e| AppError::Internal(e.to_string()))?;
-
-    let upload_url = state
-        .s3
-        .put_object()
-        .bucket(&state.config.r2_bucket)
-        .key(&key)
-        .content_type(&body.content_type)
-        .presigned(presign_config)
-        .await
-        .map_err(|e| AppError::Internal(e.to_string()))?
-        .uri()
-        .to_string();
-
-    let pending = PendingTransfer {
-        key: key.clone(),
-        size: body.size,
-    };
-
-    let pending_json =
-        serde_json::to_string(&pending).map_err(|e| AppError::Internal(e.to_string()))?;
+/// Mongo E11000, i.e. the `_id` (or another unique key) is already taken
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        &*e.kind,
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(we)) if we.code == 11000
+    )
+}
 
-    state
-        .redis
-        .set::<(), _, _>(
-            format!("pending:{id}"),
-            &pending_json,
-            Some(Expiration::EX(300)),
-            None,
-            false,
-        )
-        .await
-        .map_err(|e| AppError::Internal(format!("Redis: {e}")))?;
+/// Percent-encodes an object key as a URL path: each segment is encoded (space → `%20`, `+` → `%2B`,
+/// non-ASCII as UTF-8 bytes) but `/` separators stay literal, which is how R2 public domains map paths to keys
+fn encode_key(key: &str) -> String {
+    key.split('/').map(urlencoding::encode).collect::<Vec<_>>().join("/")
+}
 
-    Ok(HttpResponse::Ok().json(TransferResponse {
-        ok: 1,
-        id,
-        upload_url,
-        key,
-    }))
+/// Trims and de-duplicates an upload's `acl`, rejecting blank, overlong or too many entries
+fn validate_acl(acl: &[String]) -> Result<Vec<String>, AppError> {
+    if acl.len() > MAX_ACL_ENTRIES {
+        return Err(AppError::BadRequest(format!("Max {MAX_ACL_ENTRIES} acl entries")));
+    }
+    let mut ids: Vec<String> = Vec::with_capacity(acl.len());
+    for id in acl.iter().map(|id| id.trim()) {
+        if id.is_empty() || id.len() > MAX_ACL_ID_LEN {
+            return Err(AppError::BadRequest(format!(
+                "acl entries must be 1-{MAX_ACL_ID_LEN} chars"
+            )));
+        }
+        if !ids.iter().any(|seen| seen == id) {
+            ids.push(id.to_string());
+        }
+    }
+    Ok(ids)
 }
 
-// POST /transfer/{id}/done
-pub async fn complete_transfer(
-    state: web::Data<AppState>,
-    path: web::Path<String>,
-) -> Result<HttpResponse, AppError> {
-    let id = path.into_inner();
-    let redis_key = format!("pending:{id}");
+/// Limits a MongoDB query to images the caller may see: no `acl`, or one that lists them
+fn visible_to(caller: &str) -> mongodb::bson::Document {
+    mongodb::bson::doc! { "$or": [{ "acl": { "$exists": false } }, { "acl": caller }] }
+}
 
-    let pending_json: Option<String> = state
-        .redis
-        .get(&redis_key)
-        .await
-        .map_err(|e| AppError::Internal(format!("Redis: {e}")))?;
+/// Enforces an image's `acl`. Callers are identified like on the gated routes (`X-Api-Key` id, or
+/// `jwt:{sub}` for a token with `image:read`): unidentified ones get 401, unlisted ones 403.
+/// Images without an `acl` stay public.
+fn ensure_allowed(
+    state: &AppState,
+    req: &HttpRequest,
+    payload: &ImageResponsePayload,
+) -> Result<(), AppError> {
+    if payload.acl.is_empty() {
+        return Ok(());
+    }
+    let caller = auth::authorize(req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_READ)?;
+    if payload.acl.contains(&caller) {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden("Image is not shared with this caller".into()))
+    }
+}
 
-    let pending: PendingTransfer = serde_json::from_str(
-        &pending_json.ok_or_else(|| AppError::NotFound("Transfer expired or not found".into()))?,
-    )
-    .map_err(|e| AppError::Internal(e.to_string()))?;
+/// Adds `CROSS_ORIGIN_RESOURCE_POLICY` to a response answering an image request, so pages with
+/// `Cross-Origin-Embedder-Policy` can follow the redirect or show the placeholder
+fn image_response(config: &Config, mut res: HttpResponse) -> HttpResponse {
+    if let Some(policy) = config
+        .resource_policy
+        .as_deref()
+        .and_then(|p| header::HeaderValue::from_str(p).ok())
+    {
+        res.headers_mut()
+            .insert(header::HeaderName::from_static("cross-origin-resource-policy"), policy);
+    }
+    res
+}
 
-    state
-        .s3
-        .head_object()
-        .bucket(&state.config.r2_bucket)
-        .key(&pending.key)
-        .send()
-        .await
-        .map_err(|_| AppError::BadRequest("File not uploaded to storage".into()))?;
+/// What `<img>` clients get for a missing image: a redirect to `NOT_FOUND_IMAGE_URL` or, with
+/// `NOT_FOUND_PLACEHOLDER`, a transparent PNG. `None` leaves them the JSON 404.
+fn not_found_image(config: &Config) -> Option<HttpResponse> {
+    let res = if let Some(placeholder) = &config.not_found_image_url {
+        HttpResponse::Found()
+            .insert_header((header::LOCATION, placeholder.as_str()))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .finish()
+    } else if config.not_found_placeholder {
+        HttpResponse::NotFound()
+            .insert_header((header::CONTENT_TYPE, "image/png"))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .body(TRANSPARENT_PNG)
+    } else {
+        return None;
+    };
+    Some(image_response(config, res))
+}
 
-    log::info!("Verified: {id}");
+/// Keeps shared caches from storing a response for an image with an `acl`
+fn private_response(mut res: HttpResponse, payload: &ImageResponsePayload) -> HttpResponse {
+    if !payload.acl.is_empty() {
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("private, no-store"),
+        );
+    }
+    res
+}
 
-    let (_, ts) = now_parts();
-    let f = pending.key;
+/// `X-Cache: HIT|MISS` with `CACHE_STATUS_HEADER`, so CDNs and monitoring can see what the
+/// encrypted `c` flag says
+fn cache_status(config: &Config, mut res: HttpResponse, payload: &ImageResponsePayload) -> HttpResponse {
+    if config.cache_status_header {
+        let status = if payload.c == Some(1) { "HIT" } else { "MISS" };
+        res.headers_mut().insert(
+            header::HeaderName::from_static("x-cache"),
+            header::HeaderValue::from_static(status),
+        );
+    }
+    res
+}
 
-    // Convert to MB and round to 2 decimals using safe f64 conversion scaling
-    #[allow(clippy::cast_precision_loss)]
-    let s_mb = pending.size as f64 / 1_048_576.0;
-    let s = (s_mb * 100.0).round() / 100.0;
+/// Object key for a file stored today: `{date}/{name}`, or with `HASH_PREFIX` behind a two-hex-char
+/// shard hashed from that path. The shard depends only on the path, so a name still maps to one
+/// key per day and `NAME_DEDUP` keeps working.
+fn object_key(config: &Config, name: &str) -> String {
+    let (date, _) = now_parts();
+    let path = format!("{date}/{name}");
+    if config.hash_prefix {
+        format!("{}/{path}", hex::encode(&Sha256::digest(&path)[..1]))
+    } else {
+        path
+    }
+}
 
-    let url = format!(
-        "{}/{}",
-        state.config.r2_public_domain,
-        urlencoding::encode(&f)
-    );
+/// Public URL of an object key: on the CDN domain when `R2_CDN_DOMAIN` is set, else the R2 public domain
+fn public_url(config: &Config, key: &str) -> String {
+    let domain = config
+        .r2_cdn_domain
+        .as_deref()
+        .unwrap_or(&config.r2_public_domain);
+    format!("{domain}/{}", encode_key(key))
+}
 
-    state
-        .db
-        .insert_one(mongodb::bson::doc! {
-            "_id": &id,
-            "f": &f,
-            "s": s,
-            "t": ts,
-            "d": "",
-            "P": "",
-        })
-        .await
-        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+/// Image `url` shaped by `URL_TEMPLATE` so resizing CDNs get dimension hints; plain when either is missing
+fn image_url(config: &Config, key: &str, width: Option<u32>, height: Option<u32>) -> String {
+    let (Some(template), Some(w), Some(h)) = (&config.url_template, width, height) else {
+        return public_url(config, key);
+    };
+    let domain = config
+        .r2_cdn_domain
+        .as_deref()
+        .unwrap_or(&config.r2_public_domain);
+    template
+        .replace("{domain}", domain)
+        .replace("{key}", &encode_key(key))
+        .replace("{w}", &w.to_string())
+        .replace("{h}", &h.to_string())
+}
 
-    log::info!("Saved: {id}");
+/// URL of an original moved to `R2_COLD_BUCKET`; neither the CDN nor `URL_TEMPLATE` front it
+fn cold_url(config: &Config, key: &str) -> String {
+    format!("{}/{}", config.r2_cold_public_domain, encode_key(key))
+}
 
-    // Cache internal payload JSON (without cache indicator yet)
-    let internal_payload = ImageResponsePayload {
-        url,
-        f,
-        s,
-        t: ts,
-        d: String::new(),
-        p: String::new(),
-        c: None,
-    };
+/// `(cdn_url, origin_url)` for the payload, only when a CDN domain is configured
+fn cdn_and_origin(config: &Config, key: &str) -> (Option<String>, Option<String>) {
+    if config.r2_cdn_domain.is_none() {
+        return (None, None);
+    }
+    let origin = format!("{}/{}", config.r2_public_domain, encode_key(key));
+    (Some(public_url(config, key)), Some(origin))
+}
 
-    let _: Result<(), _> = state.redis.del(&redis_key).await;
+/// Stores a pending transfer in Redis, or in the `pending` collection when the cache is disabled
+async fn save_pending(state: &AppState, id: &str, pending: &PendingTransfer) -> Result<(), AppError> {
+    let pending_json =
+        serde_json::to_string(pending).map_err(|e| AppError::Internal(e.to_string()))?;
 
-    if let Ok(json) = serde_json::to_string(&internal_payload) {
-        let _: Result<(), _> = state
-            .redis
-            .set(
-                format!("i:{id}"),
-                &json,
-                Some(Expiration::EX(86400)),
+    match &state.redis {
+        Some(redis) => redis
+            .set::<(), _, _>(
+                state.config.redis_key(&state.config.pending_namespace, id),
+                &pending_json,
+                Some(Expiration::EX(PENDING_TTL_SECS)),
                 None,
                 false,
             )
-            .await;
+            .await
+            .map_err(|e| AppError::Internal(format!("Redis: {e}"))),
+        None => {
+            let (_, now) = now_parts();
+            let exp = mongodb::bson::DateTime::from_millis((now + PENDING_TTL_SECS) * 1000);
+            state
+                .pending
+                .insert_one(mongodb::bson::doc! { "_id": id, "v": pending_json, "exp": exp })
+                .await
+                .map(|_| ())
+                .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))
+        }
     }
-
-    Ok(HttpResponse::Ok().json(json!({ "ok": 1, "id": id })))
 }
 
-// GET /i/{id}
-#[allow(clippy::many_single_char_names)]
-pub async fn get_image(
-    state: web::Data<AppState>,
-    path: web::Path<String>,
-) -> Result<HttpResponse, AppError> {
-    let id = path.into_inner();
-    let cache_key = format!("i:{id}");
+async fn load_pending(state: &AppState, id: &str) -> Result<Option<PendingTransfer>, AppError> {
+    let pending_json: Option<String> = match &state.redis {
+        Some(redis) => redis
+            .get(state.config.redis_key(&state.config.pending_namespace, id))
+            .await
+            .map_err(|e| AppError::Internal(format!("Redis: {e}")))?,
+        None => {
+            // The TTL monitor only sweeps every minute, so expiry is also checked here
+            let now = mongodb::bson::DateTime::now();
+            state
+                .pending
+                .find_one(mongodb::bson::doc! { "_id": id, "exp": { "$gt": now } })
+                .await
+                .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
+                .and_then(|doc| doc.get_str("v").ok().map(String::from))
+        }
+    };
 
-    // Check Redis cache (stores internal payload JSON)
-    if let Some(cached_json) = state
-        .redis
-        .get::<Option<String>, _>(&cache_key)
-        .await
-        .unwrap_or(None)
-    {
-        if let Ok(mut payload_obj) = serde_json::from_str::<ImageResponsePayload>(&cached_json) {
-            payload_obj.c = Some(1); // Set cache flag to true
-            let final_json = serde_json::to_string(&payload_obj).unwrap();
-            let encrypted_hex = encrypt_payload(&final_json, &state.config.encryption_key)?;
+    pending_json
+        .map(|json| serde_json::from_str(&json).map_err(|e| AppError::Internal(e.to_string())))
+        .transpose()
+}
 
-            return Ok(HttpResponse::Ok().json(ObfuscatedResponse {
-                ok: 1,
-                payload: encrypted_hex,
-            }));
+/// Best-effort removal of a pending transfer. A record that survives a failed delete only lets
+/// the transfer be completed again, which resolves to the image already stored.
+async fn clear_pending(state: &AppState, id: &str) {
+    match &state.redis {
+        Some(redis) => {
+            let _: Result<(), _> = redis.del(state.config.redis_key(&state.config.pending_namespace, id)).await;
+        }
+        None => {
+            let _ = state.pending.delete_one(mongodb::bson::doc! { "_id": id }).await;
         }
     }
+}
 
-    let doc = state
-        .db
-        .find_one(mongodb::bson::doc! { "_id": &id })
-        .await
-        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
-        .ok_or_else(|| AppError::NotFound("Image not found".into()))?;
+/// Up to `MAX_PENDING_LIST` outstanding transfers as `(id, pending, remaining ttl secs)`,
+/// plus whether more exist
+async fn list_pending(state: &AppState) -> Result<(Vec<(String, PendingTransfer, i64)>, bool), AppError> {
+    let mut items = Vec::new();
 
-    let f = doc.get_str("f").unwrap_or("").to_string();
-    let s = doc.get_f64("s").unwrap_or(0.0);
-    let t = doc.get_i64("t").unwrap_or(0);
-    let d = doc.get_str("d").unwrap_or("").to_string();
-    let p = doc.get_str("P").unwrap_or("").to_string();
+    match &state.redis {
+        Some(redis) => {
+            let prefix = state.config.redis_key(&state.config.pending_namespace, "");
+            // Dropping the stream cancels the background SCAN once the cap is hit
+            let mut keys = redis.scan_buffered(format!("{prefix}*"), Some(100), None);
+            while let Some(key) = keys
+                .try_next()
+                .await
+                .map_err(|e| AppError::Internal(format!("Redis: {e}")))?
+            {
+                if items.len() == MAX_PENDING_LIST {
+                    return Ok((items, true));
+                }
+                let Some(key) = key.into_string() else { continue };
+                let (json, ttl): (Option<String>, i64) = futures_util::try_join!(
+                    redis.get(&key),
+                    redis.ttl(&key),
+                )
+                .map_err(|e| AppError::Internal(format!("Redis: {e}")))?;
+                // Expired between SCAN and GET
+                let Some(pending) = json.and_then(|j| serde_json::from_str(&j).ok()) else {
+                    continue;
+                };
+                let id = key.strip_prefix(&prefix).unwrap_or(&key).to_string();
+                items.push((id, pending, ttl));
+            }
+        }
+        None => {
+            let now = mongodb::bson::DateTime::now();
+            let docs: Vec<mongodb::bson::Document> = state
+                .pending
+                .find(mongodb::bson::doc! { "exp": { "$gt": now } })
+                .sort(mongodb::bson::doc! { "exp": 1 })
+                .limit(MAX_PENDING_LIST as i64 + 1)
+                .await
+                .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
+                .try_collect()
+                .await
+                .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
 
-    let url = format!(
-        "{}/{}",
-        state.config.r2_public_domain,
-        urlencoding::encode(&f)
-    );
+            for doc in &docs {
+                if items.len() == MAX_PENDING_LIST {
+                    return Ok((items, true));
+                }
+                let (Ok(id), Ok(json), Ok(exp)) =
+                    (doc.get_str("_id"), doc.get_str("v"), doc.get_datetime("exp"))
+                else {
+                    continue;
+                };
+                let Ok(pending) = serde_json::from_str(json) else { continue };
+                let ttl = (exp.timestamp_millis() - now.timestamp_millis()) / 1000;
+                items.push((id.to_string(), pending, ttl));
+            }
+        }
+    }
 
-    let payload_obj = ImageResponsePayload {
-        url,
-        f,
-        s,
-        t,
-        d,
-        p,
-        c: None,
-    };
+    Ok((items, false))
+}
 
-    // Cache internal payload JSON (24h)
-    if let Ok(json) = serde_json::to_string(&payload_obj) {
-        let _: Result<(), _> = state
-            .redis
+/// Cached payload JSON; always a miss when the cache is disabled
+async fn cache_get(state: &AppState, id: &str) -> Option<String> {
+    let redis = state.redis.as_ref()?;
+    redis
+        .get::<Option<String>, _>(state.config.redis_key("i", id))
+        .await
+        .unwrap_or(None)
+}
+
+async fn cache_set(state: &AppState, id: &str, json: &str) {
+    if let Some(redis) = &state.redis {
+        let _: Result<(), _> = redis
             .set(
-                &cache_key,
-                &json,
-                Some(Expiration::EX(86400)),
+                state.config.redis_key("i", id),
+                json,
+                Some(Expiration::EX(CACHE_TTL_SECS)),
                 None,
                 false,
             )
             .await;
     }
+}
 
-    let final_json = serde_json::to_string(&payload_obj).unwrap();
-    let encrypted_hex = encrypt_payload(&final_json, &state.config.encryption_key)?;
+/// Drops both the cached payload and any negative-cache marker for these ids
+async fn cache_del(state: &AppState, ids: &[String]) {
+    if let Some(redis) = &state.redis {
+        let keys: Vec<String> = ids
+            .iter()
+            .flat_map(|id| [state.config.redis_key("i", id), state.config.redis_key("miss", id)])
+            .collect();
+        let _: Result<(), _> = redis.del(keys).await;
+    }
+}
 
-    Ok(HttpResponse::Ok().json(ObfuscatedResponse {
-        ok: 1,
-        payload: encrypted_hex,
-    }))
+/// Transfer response handed out for an identical request within `PRESIGN_CACHE_SECS`
+async fn cached_presign(state: &AppState, fingerprint: &str) -> Option<String> {
+    let redis = state.redis.as_ref()?;
+    redis
+        .get::<Option<String>, _>(state.config.redis_key("presign", fingerprint))
+        .await
+        .unwrap_or(None)
 }
 
-// GET /health
-pub async fn health() -> HttpResponse {
-    HttpResponse::Ok().json(json!({ "ok": 1 }))
+async fn cache_presign(state: &AppState, fingerprint: &str, json: &str) {
+    if let Some(redis) = &state.redis {
+        let _: Result<(), _> = redis
+            .set(
+                state.config.redis_key("presign", fingerprint),
+                json,
+                Some(Expiration::EX(state.config.presign_cache_secs)),
+                None,
+                false,
+            )
+            .await;
+    }
+}
+
+/// Whether a recent lookup already found nothing for this id (`MISS_CACHE_SECS`)
+async fn miss_cached(state: &AppState, id: &str) -> bool {
+    let Some(redis) = state.redis.as_ref().filter(|_| state.config.miss_cache_secs > 0) else {
+        return false;
+    };
+    redis
+        .exists::<u32, _>(state.config.redis_key("miss", id))
+        .await
+        .is_ok_and(|n| n > 0)
+}
+
+async fn cache_miss(state: &AppState, id: &str) {
+    if let Some(redis) = state.redis.as_ref().filter(|_| state.config.miss_cache_secs > 0) {
+        let _: Result<(), _> = redis
+            .set(
+                state.config.redis_key("miss", id),
+                "1",
+                Some(Expiration::EX(state.config.miss_cache_secs)),
+                None,
+                false,
+            )
+            .await;
+    }
+}
+
+/// Looks an image document up in MongoDB, sharing one query between concurrent requests for
+/// the same id so a burst of cache misses doesn't become a burst of identical `find_one`s
+async fn find_image_doc(
+    state: &AppState,
+    id: &str,
+) -> Result<Option<mongodb::bson::Document>, AppError> {
+    let cell = {
+        let mut lookups = state.lookups.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(lookups.entry(id.to_string()).or_default())
+    };
+
+    let found = cell
+        .get_or_try_init(|| async {
+            state
+                .images_for(id)
+                .find_one(mongodb::bson::doc! { "_id": id })
+                .await
+                .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))
+        })
+        .await
+        .cloned();
+
+    // Settled: later requests should see fresh data, not this result
+    let mut lookups = state.lookups.lock().unwrap_or_else(|e| e.into_inner());
+    if lookups.get(id).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+        lookups.remove(id);
+    }
+
+    found
+}
+
+/// Normalizes a client-supplied file name (lowercased with `LOWERCASE_KEYS`) and rejects reserved ones
+fn sanitize_name(raw: &str, config: &Config) -> Result<String, AppError> {
+    // Visually identical NFC/NFD spellings must map to one key; NFKC also folds compatibility forms
+    let normalized: String = if config.name_nfkc {
+        raw.trim().nfkc().collect()
+    } else {
+        raw.trim().nfc().collect()
+    };
+    let name = normalized.as_str();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("Name cannot be empty".into()));
+    }
+
+    let basename = name.rsplit(['/', '\\']).next().unwrap_or(name).to_lowercase();
+    if config.blocked_filenames.contains(&basename) {
+        return Err(AppError::BadRequest(format!("File name '{basename}' is reserved")));
+    }
+
+    // Applied here so the pending record, the object key and the document all agree
+    if config.lowercase_keys {
+        Ok(name.to_lowercase())
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+/// ASCII-only form of a file name for `SAFE_KEYS`. Each path segment keeps letters, digits, `.`,
+/// `_` and `-`, with runs of anything else collapsed to one `-`; `.`/`..` segments are dropped and
+/// a name left without a stem becomes `file` (`写真.jpg` → `file.jpg`).
+fn safe_key_name(name: &str) -> String {
+    let segments: Vec<String> = name
+        .split(['/', '\\'])
+        .filter_map(|segment| {
+            let mut out = String::with_capacity(segment.len());
+            for c in segment.chars() {
+                if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                    out.push(c);
+                } else if !out.ends_with('-') {
+                    out.push('-');
+                }
+            }
+            let out = out.trim_matches('-');
+            if out.chars().all(|c| c == '.') {
+                None
+            } else if out.starts_with('.') {
+                Some(format!("file{out}"))
+            } else {
+                Some(out.to_string())
+            }
+        })
+        .collect();
+    if segments.is_empty() {
+        "file".into()
+    } else {
+        segments.join("/")
+    }
+}
+
+/// `Content-Disposition` that keeps images displaying inline but saves them under `name`:
+/// an ASCII fallback plus the exact name as RFC 5987 `filename*`
+fn content_disposition(name: &str) -> String {
+    let basename = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    format!(
+        "inline; filename=\"{}\"; filename*=UTF-8''{}",
+        safe_key_name(basename),
+        urlencoding::encode(basename)
+    )
+}
+
+/// Matches a lowercased MIME type against exact entries, `type/*` subtype wildcards, or a bare `*`
+fn format_allowed(allowed: &[String], content_type: &str) -> bool {
+    allowed.iter().any(|pattern| {
+        pattern == "*"
+            || pattern == content_type
+            || pattern.strip_suffix("/*").is_some_and(|main| {
+                content_type
+                    .split_once('/')
+                    .is_some_and(|(ty, sub)| ty == main && !sub.is_empty())
+            })
+    })
+}
+
+fn format_blocked(content_type: &str) -> AppError {
+    AppError::BadRequest(format!("File format '{content_type}' is blocked"))
+}
+
+/// Applies `ALLOWED_FORMATS`, then `BLOCKED_FORMATS`, which wins for types both match
+fn check_format(config: &Config, content_type: &str) -> Result<(), AppError> {
+    if !format_allowed(&config.allowed_formats, content_type) {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported file format. Allowed: {}",
+            config.allowed_formats.join(", ")
+        )));
+    }
+    if format_allowed(&config.blocked_formats, content_type) {
+        return Err(format_blocked(content_type));
+    }
+    Ok(())
+}
+
+/// With `STRICT_EXTENSION`, requires the file name's extension to map to `content_type`
+fn check_extension(config: &Config, name: &str, content_type: &str) -> Result<(), AppError> {
+    if !config.strict_extension {
+        return Ok(());
+    }
+    let ext = name.trim().rsplit_once('.').map_or("", |(_, ext)| ext);
+    match mime::from_filename(name.trim()) {
+        Some(mapped) if mapped == content_type => Ok(()),
+        Some(_) => Err(AppError::BadRequest(format!(
+            "File extension .{ext} doesn't match type {content_type}"
+        ))),
+        None => Err(AppError::BadRequest(format!(
+            "File name needs an extension matching type {content_type}"
+        ))),
+    }
+}
+
+fn storage_outage(retry_after: u64) -> AppError {
+    AppError::Outage("Storage unavailable, retry later".into(), retry_after)
+}
+
+/// Short-circuits S3 work while the breaker is open, so requests fail fast instead of each
+/// waiting out a timeout against a store that is down
+fn s3_available(state: &AppState) -> Result<(), AppError> {
+    let (_, now) = now_parts();
+    let open_until = state.s3_open_until.load(Ordering::Relaxed);
+    if open_until > now {
+        return Err(storage_outage(open_until.abs_diff(now)));
+    }
+    Ok(())
+}
+
+/// Any answer from S3, even an error status, shows it is reachable
+fn s3_ok(state: &AppState) {
+    state.s3_failures.store(0, Ordering::Relaxed);
+}
+
+/// Counts connection failures, timeouts and 5xx toward `S3_BREAKER_THRESHOLD`, opening the
+/// breaker once it is reached. A failure after the breaker closes again, before any success,
+/// reopens it straight away. Returns whether the error was an outage.
+fn note_s3_failure<E>(state: &AppState, e: &SdkError<E>) -> bool
+where
+    SdkError<E>: std::fmt::Display,
+{
+    let outage = matches!(e, SdkError::DispatchFailure(_) | SdkError::TimeoutError(_))
+        || e.raw_response().is_some_and(|res| res.status().is_server_error());
+    if !outage {
+        return false;
+    }
+    let failures = state.s3_failures.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+    let threshold = state.config.s3_breaker_threshold;
+    if threshold > 0 && failures >= threshold {
+        let (_, now) = now_parts();
+        let until = now.saturating_add_unsigned(state.config.s3_breaker_secs);
+        if state.s3_open_until.swap(until, Ordering::Relaxed) <= now {
+            log::error!(
+                "S3 breaker open for {}s after {failures} failures: {e}",
+                state.config.s3_breaker_secs
+            );
+        }
+    } else {
+        log::warn!("S3 unreachable ({failures} in a row): {e}");
+    }
+    true
+}
+
+/// Maps an S3 error: outages become `503` with `Retry-After`, anything else a `500`
+fn s3_error<E>(state: &AppState, e: &SdkError<E>) -> AppError
+where
+    SdkError<E>: std::fmt::Display,
+{
+    if note_s3_failure(state, e) {
+        storage_outage(state.config.s3_breaker_secs)
+    } else {
+        AppError::Internal(format!("S3: {e}"))
+    }
+}
+
+/// Downloads an object, or only its first `max` bytes when given
+async fn fetch_object(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    max: Option<u64>,
+) -> Result<Vec<u8>, AppError> {
+    s3_available(state)?;
+    let object = state
+        .s3
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .set_range(max.map(|m| format!("bytes=0-{}", m - 1)))
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+
+    let body = object
+        .body
+        .collect()
+        .await
+        .map_err(|e| AppError::Internal(format!("S3: {e}")))?;
+
+    Ok(body.into_bytes().to_vec())
+}
+
+/// Deletes one object, through the storage breaker like every other S3 call
+async fn delete_object(state: &AppState, bucket: &str, key: &str) -> Result<(), AppError> {
+    s3_available(state)?;
+    state
+        .s3
+        .delete_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+    Ok(())
+}
+
+/// Stores a rendered WebP (a variant or the negotiated copy) in `R2_BUCKET`
+async fn put_webp(state: &AppState, key: &str, bytes: Vec<u8>) -> Result<(), AppError> {
+    s3_available(state)?;
+    state
+        .s3
+        .put_object()
+        .bucket(&state.config.r2_bucket)
+        .key(key)
+        .content_type("image/webp")
+        .set_cache_control(state.config.object_cache_control.clone())
+        .set_metadata(object_metadata(&state.config))
+        .body(ByteStream::from(bytes))
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+    Ok(())
+}
+
+/// Best-effort EXIF read; a broken or missing EXIF block never fails the completion
+async fn read_meta(state: &AppState, key: &str) -> Option<ImageMeta> {
+    match fetch_object(state, &state.config.r2_bucket, key, Some(META_SCAN_BYTES)).await {
+        Ok(bytes) => metadata::extract(&bytes),
+        Err(e) => {
+            log::warn!("Metadata skipped for {key}: {e}");
+            None
+        }
+    }
+}
+
+/// HEADs a just-PUT object, retrying a 404 for `COMPLETE_HEAD_ATTEMPTS` in case the store
+/// hasn't made it visible yet. Only a 404 that outlasts the retries means it was never uploaded.
+async fn head_uploaded(
+    state: &AppState,
+    key: &str,
+    checksum: bool,
+) -> Result<HeadObjectOutput, AppError> {
+    s3_available(state)?;
+    let attempts = state.config.complete_head_attempts;
+    let mut attempt = 1;
+    loop {
+        match state
+            .s3
+            .head_object()
+            .bucket(&state.config.r2_bucket)
+            .key(key)
+            .set_checksum_mode(checksum.then_some(ChecksumMode::Enabled))
+            .send()
+            .await
+        {
+            Ok(head) => {
+                s3_ok(state);
+                return Ok(head);
+            }
+            Err(e) if e.as_service_error().is_some_and(HeadObjectError::is_not_found) => {
+                s3_ok(state);
+                if attempt >= attempts {
+                    return Err(AppError::BadRequest("File not uploaded to storage".into()));
+                }
+            }
+            Err(e) => return Err(s3_error(state, &e)),
+        }
+        attempt += 1;
+        actix_web::rt::time::sleep(Duration::from_millis(state.config.complete_head_delay_ms)).await;
+    }
+}
+
+/// Checks the uploaded object against the size declared at `POST /transfer`. The presigned PUT
+/// doesn't bind a length, so an object over `MAX_SIZE_MB` is rejected here. Other mismatches are
+/// logged (or rejected with `STRICT_SIZE`) and the real size is what gets stored.
+fn reconcile_size(
+    config: &Config,
+    id: &str,
+    pending: &mut PendingTransfer,
+    actual: u64,
+) -> Result<(), AppError> {
+    if actual == pending.size {
+        return Ok(());
+    }
+    if actual > config.max_size {
+        return Err(AppError::LargePayload(format!(
+            "Max {}MB, uploaded file is {actual} bytes",
+            config.max_size_mb
+        )));
+    }
+    if config.strict_size {
+        return Err(AppError::Unprocessable(format!(
+            "Upload rejected: declared {} bytes but uploaded {actual}",
+            pending.size
+        )));
+    }
+    log::warn!(
+        "Size mismatch for {id}: declared {} bytes, uploaded {actual}",
+        pending.size
+    );
+    pending.size = actual;
+    Ok(())
+}
+
+/// A CRC32C as R2 reports it in `x-amz-checksum-crc32c`: the big-endian bytes, base64-encoded
+fn crc32c_header(crc: u32) -> String {
+    BASE64.encode(crc.to_be_bytes())
+}
+
+/// Normalizes a client-supplied CRC32C, or `None` when it isn't 4 base64-encoded bytes
+fn parse_crc32c(raw: &str) -> Option<String> {
+    let bytes: [u8; 4] = BASE64.decode(raw.trim()).ok()?.try_into().ok()?;
+    Some(crc32c_header(u32::from_be_bytes(bytes)))
+}
+
+/// Compares the checksum R2 computed for an upload with the one signed at `POST /transfer`.
+/// R2 already refuses a PUT that doesn't match, so a mismatch here means the object was
+/// replaced in between, and it is rejected. An object without a reported checksum is logged and
+/// keeps the declared one.
+fn verify_crc32c(id: &str, key: &str, expected: &str, stored: Option<&str>) -> Result<(), AppError> {
+    match stored {
+        Some(stored) if stored == expected => Ok(()),
+        Some(stored) => Err(AppError::Unprocessable(format!(
+            "Upload rejected: CRC32C is {stored}, expected {expected}"
+        ))),
+        None => {
+            log::warn!("No CRC32C reported for {id} ({key}), keeping the declared one");
+            Ok(())
+        }
+    }
+}
+
+/// With `REQUIRE_TRANSFER_AUTH`, uploads need an API key or a `transfer:write` token. Without
+/// it they may still send one, which is then checked the same way and identifies the uploader.
+/// Returns the caller id, which becomes the image's tenant, and its concurrency slot.
+fn authorize_transfer(
+    state: &AppState,
+    req: &HttpRequest,
+) -> Result<(Option<String>, Option<KeySlot>), AppError> {
+    let presented = req.headers().contains_key(auth::API_KEY_HEADER)
+        || (state.jwt.is_some()
+            && req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("Bearer ")));
+    if !state.config.require_transfer_auth && !presented {
+        return Ok((None, None));
+    }
+    let key_id = auth::authorize(req, &state.config, state.jwt.as_ref(), auth::SCOPE_TRANSFER_WRITE)?;
+    let slot = claim_key_slot(state, &key_id)?;
+    Ok((Some(key_id), slot))
+}
+
+/// Rejects new transfers outside `UPLOAD_WINDOW`
+fn ensure_upload_window(config: &Config) -> Result<(), AppError> {
+    let Some((start, end)) = config.upload_window else {
+        return Ok(());
+    };
+    let (_, now) = now_parts();
+    let minute = u32::try_from(now.rem_euclid(86400) / 60).unwrap_or(0);
+    // `start > end` is a window that wraps past midnight, e.g. 22:00-06:00
+    let open = if start <= end {
+        (start..end).contains(&minute)
+    } else {
+        minute >= start || minute < end
+    };
+    if open {
+        Ok(())
+    } else {
+        Err(AppError::Unavailable(format!(
+            "Uploads are accepted {:02}:{:02}-{:02}:{:02} UTC",
+            start / 60,
+            start % 60,
+            end / 60,
+            end % 60
+        )))
+    }
+}
+
+/// Hides images still under the `PUBLISH_DELAY_SECS` embargo, or past their expiry but not
+/// yet swept, as if they didn't exist
+fn ensure_published(
+    config: &Config,
+    payload: ImageResponsePayload,
+) -> Result<ImageResponsePayload, AppError> {
+    let (_, now) = now_parts();
+    if now < payload.t + config.publish_delay_secs || payload.exp.is_some_and(|exp| now >= exp) {
+        return Err(AppError::NotFound("Image not found".into()));
+    }
+    Ok(payload)
+}
+
+/// Lifetime for an upload that didn't ask for one: the first `RETENTION_POLICY` rule matching
+/// its caller or type. `None` keeps it forever.
+fn default_retention(config: &Config, owner: Option<&str>, content_type: &str) -> Option<u64> {
+    config
+        .retention_policy
+        .iter()
+        .find(|(class, _)| match class {
+            RetentionClass::Anonymous => owner.is_none(),
+            RetentionClass::Authenticated => owner.is_some(),
+            RetentionClass::Caller(id) => owner == Some(id.as_str()),
+            RetentionClass::Format(pattern) => {
+                format_allowed(std::slice::from_ref(pattern), content_type)
+            }
+            RetentionClass::Any => true,
+        })
+        .and_then(|(_, lifetime)| *lifetime)
+}
+
+/// Rejects completions once the catalog reaches `MAX_TOTAL_IMAGES` (count cached briefly)
+async fn ensure_capacity(state: &AppState, max: u64) -> Result<(), AppError> {
+    let (_, now) = now_parts();
+    let count = if now - state.image_count_at.load(Ordering::Relaxed) < IMAGE_COUNT_TTL_SECS {
+        state.image_count.load(Ordering::Relaxed)
+    } else {
+        let counts = futures_util::future::try_join_all(
+            state.images.iter().map(|images| images.estimated_document_count().into_future()),
+        )
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+        let count = counts.into_iter().sum();
+        state.image_count.store(count, Ordering::Relaxed);
+        state.image_count_at.store(now, Ordering::Relaxed);
+        count
+    };
+
+    if count >= max {
+        return Err(AppError::InsufficientStorage(format!(
+            "Image limit of {max} reached"
+        )));
+    }
+    Ok(())
+}
+
+/// Streams the uploaded object through clamd. Infected uploads are rejected;
+/// scanner errors reject the completion (retryable) unless `CLAMAV_FAIL_OPEN=true`.
+async fn scan_upload(state: &AppState, id: &str, key: &str, addr: &str) -> Result<(), AppError> {
+    // Storage failures are storage errors, never a reason to skip the scan
+    s3_available(state)?;
+    let object = state
+        .s3
+        .get_object()
+        .bucket(&state.config.r2_bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+    let body = stream::unfold(object.body, |mut body| async move {
+        body.next()
+            .await
+            .map(|chunk| (chunk.map_err(io::Error::other), body))
+    });
+    let scan = av::scan(addr, body);
+
+    let verdict = actix_web::rt::time::timeout(
+        Duration::from_secs(state.config.clamav_timeout_secs),
+        scan,
+    )
+    .await
+    .unwrap_or_else(|_| Err("scan timed out".into()));
+
+    match verdict {
+        Ok(Verdict::Clean) => Ok(()),
+        Ok(Verdict::Infected(reply)) => {
+            log::warn!("Malware in {id} ({key}): {reply}");
+            Err(AppError::Unprocessable(
+                "Upload rejected: malware detected".into(),
+            ))
+        }
+        Err(e) if state.config.clamav_fail_open => {
+            log::warn!("AV scan skipped for {id}: {e}");
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("AV scan failed for {id}: {e}");
+            Err(AppError::Unavailable(
+                "Virus scan unavailable, retry later".into(),
+            ))
+        }
+    }
+}
+
+/// `OBJECT_METADATA` in the shape the S3 builders take; `None` when unset
+fn object_metadata(config: &Config) -> Option<HashMap<String, String>> {
+    Some(config.object_metadata.clone()).filter(|m| !m.is_empty())
+}
+
+/// Whether a failed completion step refused the upload itself, rather than failing in a way a
+/// retry could get past. Refused uploads are deleted along with their pending record; after any
+/// other failure both are kept so the client can retry `POST /transfer/{id}/done`.
+fn rejects_upload(e: &AppError) -> bool {
+    matches!(e, AppError::Unprocessable(_) | AppError::LargePayload(_))
+}
+
+/// Passes a completion step's result through, discarding the upload when it was refused
+async fn or_discard<T>(
+    state: &AppState,
+    id: &str,
+    key: &str,
+    result: Result<T, AppError>,
+) -> Result<T, AppError> {
+    if result.as_ref().is_err_and(rejects_upload) {
+        discard_upload(state, id, key).await;
+    }
+    result
+}
+
+/// Removes a rejected upload and its pending record so the id can't be completed later
+async fn discard_upload(state: &AppState, id: &str, key: &str) {
+    if let Err(e) = delete_object(state, &state.config.r2_bucket, key).await {
+        log::error!("Failed to delete rejected {key}: {e}");
+    }
+    clear_pending(state, id).await;
+}
+
+/// Re-checks `STRICT_EXTENSION` against the uploaded bytes, since the presigned PUT only saw
+/// the declared type. Recognised raster content under another format's extension is rejected;
+/// anything the sniffer doesn't know passes on the intake check alone.
+async fn confirm_extension(state: &AppState, key: &str) -> Result<(), AppError> {
+    let bytes = fetch_object(state, &state.config.r2_bucket, key, Some(SNIFF_BYTES)).await?;
+    let Some(detected) = imaging::sniff(&bytes) else {
+        return Ok(());
+    };
+    if mime::from_filename(key) == Some(detected) {
+        return Ok(());
+    }
+    Err(AppError::Unprocessable(format!(
+        "Upload rejected: content is {detected}, which doesn't match its file extension"
+    )))
+}
+
+/// Enforces `MIN_WIDTH`, `MIN_HEIGHT` and `ASPECT_RATIO` on a raster upload's upright
+/// dimensions. Uploads that miss them, or whose header can't be read, are rejected.
+async fn check_dimensions(state: &AppState, id: &str, key: &str) -> Result<(), AppError> {
+    let bucket = &state.config.r2_bucket;
+    // Only the header is parsed, which usually sits within `META_SCAN_BYTES` along with the
+    // EXIF orientation. Large EXIF/ICC segments can push it further, so a prefix that was cut
+    // short is retried with the whole object before the upload is refused.
+    let prefix = fetch_object(state, bucket, key, Some(META_SCAN_BYTES)).await?;
+    let truncated = prefix.len() as u64 >= META_SCAN_BYTES;
+    let mut dimensions = read_dimensions(state, prefix).await?;
+    if dimensions.is_err() && truncated {
+        let whole = fetch_object(state, bucket, key, None).await?;
+        dimensions = read_dimensions(state, whole).await?;
+    }
+
+    let problem = match dimensions {
+        Err(e) => {
+            log::warn!("Dimensions unreadable for {id} ({key}): {e}");
+            Some("its dimensions could not be read".to_string())
+        }
+        Ok((w, h)) => {
+            let config = &state.config;
+            if let Some(min) = config.min_width.filter(|&min| w < min) {
+                Some(format!("image is {w}x{h}, minimum width is {min}"))
+            } else if let Some(min) = config.min_height.filter(|&min| h < min) {
+                Some(format!("image is {w}x{h}, minimum height is {min}"))
+            } else if let Some((rw, rh)) = config
+                .aspect_ratio
+                .filter(|&(rw, rh)| !matches_aspect(w, h, rw, rh))
+            {
+                Some(format!("image is {w}x{h}, aspect ratio must be {rw}:{rh}"))
+            } else {
+                None
+            }
+        }
+    };
+    match problem {
+        Some(problem) => Err(AppError::Unprocessable(format!("Upload rejected: {problem}"))),
+        None => Ok(()),
+    }
+}
+
+/// Parses upright dimensions from image bytes under a `processing` permit, taken only once the
+/// bytes are in hand so the slot isn't held across the fetch
+async fn read_dimensions(
+    state: &AppState,
+    bytes: Vec<u8>,
+) -> Result<Result<(u32, u32), String>, AppError> {
+    let permit = Arc::clone(&state.processing)
+        .acquire_owned()
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    web::block(move || {
+        let _permit = permit;
+        imaging::dimensions(&bytes)
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))
+}
+
+/// Whether `w`x`h` is within `ASPECT_TOLERANCE` of `rw`:`rh`, so rounding to whole pixels passes
+fn matches_aspect(w: u32, h: u32, rw: u32, rh: u32) -> bool {
+    if h == 0 {
+        return false;
+    }
+    let actual = f64::from(w) / f64::from(h);
+    let wanted = f64::from(rw) / f64::from(rh);
+    (actual - wanted).abs() <= wanted * ASPECT_TOLERANCE
+}
+
+/// Rewrites an uploaded SVG without scripts, event handlers or external references.
+/// Returns the cleaned size and CRC32C when the object was replaced; unparseable SVGs are
+/// rejected.
+async fn sanitize_svg_upload(
+    state: &AppState,
+    id: &str,
+    upload: &PendingTransfer,
+) -> Result<Option<(u64, String)>, AppError> {
+    let key = upload.key.as_str();
+    let bytes = fetch_object(state, &state.config.r2_bucket, key, None).await?;
+
+    let cleaned = web::block(move || {
+        let text = std::str::from_utf8(&bytes).map_err(|_| "not UTF-8".to_string())?;
+        let cleaned = svg::sanitize(text)?;
+        Ok::<_, String>((cleaned != text).then_some(cleaned))
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let cleaned = match cleaned {
+        Ok(Some(cleaned)) => cleaned,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            log::warn!("Unparseable SVG in {id} ({key}): {e}");
+            return Err(AppError::Unprocessable(
+                "Upload rejected: SVG could not be parsed".into(),
+            ));
+        }
+    };
+
+    let size = cleaned.len() as u64;
+    let crc = crc32c_header(crc32c::crc32c(cleaned.as_bytes()));
+    state
+        .s3
+        .put_object()
+        .bucket(&state.config.r2_bucket)
+        .key(key)
+        .content_type("image/svg+xml")
+        .set_cache_control(state.config.object_cache_control.clone())
+        .set_content_disposition(upload.orig_name.as_deref().map(content_disposition))
+        .set_metadata(object_metadata(&state.config))
+        .checksum_crc32_c(&crc)
+        .body(ByteStream::from(cleaned.into_bytes()))
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+
+    log::info!("Sanitized SVG {id}: {size} bytes");
+    Ok(Some((size, crc)))
+}
+
+/// Source dimensions, dominant color, perceptual hash and the uploaded WebP renditions as `(width, height, key)`
+struct Processed {
+    width: u32,
+    height: u32,
+    color: Option<String>,
+    phash: Option<String>,
+    variants: Vec<(u32, u32, String)>,
+}
+
+impl Processed {
+    /// Document fields for the computed values: `w`, `h`, and `color`/`phash`/`variants` when present
+    fn fields(&self) -> mongodb::bson::Document {
+        let mut fields = mongodb::bson::doc! {
+            "w": i64::from(self.width),
+            "h": i64::from(self.height),
+        };
+        if let Some(color) = &self.color {
+            fields.insert("color", color);
+        }
+        if let Some(phash) = &self.phash {
+            fields.insert("phash", phash);
+        }
+        if !self.variants.is_empty() {
+            let variants: Vec<mongodb::bson::Document> = self
+                .variants
+                .iter()
+                .map(|(w, h, k)| mongodb::bson::doc! { "w": i64::from(*w), "h": i64::from(*h), "k": k })
+                .collect();
+            fields.insert("variants", variants);
+        }
+        fields
+    }
+}
+
+/// Why `render_blocking` produced nothing
+enum RenderError {
+    /// Still running after `MAX_DECODE_MS`
+    TimedOut,
+    Failed(String),
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TimedOut => write!(f, "decode exceeded MAX_DECODE_MS"),
+            Self::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Runs decode/encode work on the blocking pool, giving up after `MAX_DECODE_MS`. A blocking
+/// task can't be interrupted, so the processing permit moves into it: a timed-out decode keeps
+/// its `MAX_CONCURRENT_PROCESSING` slot until it really finishes and can't stack up CPU work.
+async fn render_blocking<T, F>(
+    state: &AppState,
+    permit: OwnedSemaphorePermit,
+    work: F,
+) -> Result<T, RenderError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    let task = web::block(move || {
+        let _permit = permit;
+        work()
+    });
+    let done = if state.config.max_decode_ms == 0 {
+        task.await
+    } else {
+        actix_web::rt::time::timeout(Duration::from_millis(state.config.max_decode_ms), task)
+            .await
+            .map_err(|_| RenderError::TimedOut)?
+    };
+    match done {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(RenderError::Failed(format!("decode failed: {e}"))),
+        Err(e) => Err(RenderError::Failed(e.to_string())),
+    }
+}
+
+/// Decodes the upload once to compute its dominant color (`DOMINANT_COLOR`) and perceptual hash
+/// (`PHASH`), and to render
+/// `VARIANT_WIDTHS` WebP variants under `variants/{id}/`.
+/// Best-effort: a decode or upload failure never invalidates the original upload, but one that
+/// runs past `MAX_DECODE_MS` is treated as hostile and answered with 422.
+async fn process_image(
+    state: &AppState,
+    id: &str,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<Processed>, AppError> {
+    let Ok(permit) = Arc::clone(&state.processing).acquire_owned().await else {
+        return Ok(None);
+    };
+
+    let bytes = match fetch_object(state, bucket, key, None).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::warn!("Processing skipped for {id}: {e}");
+            return Ok(None);
+        }
+    };
+
+    let widths = state.config.variant_widths.clone();
+    let want_color = state.config.dominant_color;
+    let want_phash = state.config.phash;
+    let max_decode = state.config.max_decode_bytes;
+    let quality = state.config.variant_quality;
+    let rendered = render_blocking(state, permit, move || {
+        let img = imaging::decode(&bytes, max_decode)?;
+        let color = want_color.then(|| imaging::dominant_color(&img)).flatten();
+        let phash = want_phash.then(|| imaging::dhash(&img));
+        let variants = imaging::webp_variants(&img, &widths, quality);
+        Ok((img.width(), img.height(), color, phash, variants))
+    })
+    .await;
+
+    let (width, height, color, phash, encoded) = match rendered {
+        Ok(rendered) => rendered,
+        Err(RenderError::TimedOut) => {
+            log::warn!("Processing aborted for {id}: decode exceeded {}ms", state.config.max_decode_ms);
+            return Err(AppError::Unprocessable("Upload rejected: image took too long to decode".into()));
+        }
+        Err(e) => {
+            log::warn!("Processing skipped for {id}: {e}");
+            return Ok(None);
+        }
+    };
+
+    let mut variants = Vec::with_capacity(encoded.len());
+    for variant in encoded {
+        let variant_key = format!("variants/{id}/{}.webp", variant.width);
+        match put_webp(state, &variant_key, variant.bytes).await {
+            Ok(()) => variants.push((variant.width, variant.height, variant_key)),
+            Err(e) => log::warn!("Variant upload failed for {variant_key}: {e}"),
+        }
+    }
+
+    Ok(Some(Processed {
+        width,
+        height,
+        color,
+        phash,
+        variants,
+    }))
+}
+
+/// Reads `variants` sub-documents back into payload entries with public URLs
+fn doc_variants(config: &Config, doc: &mongodb::bson::Document) -> Vec<ImageVariant> {
+    doc.get_array("variants")
+        .map(|all| {
+            all.iter()
+                .filter_map(|v| {
+                    let v = v.as_document()?;
+                    Some(ImageVariant {
+                        w: u32::try_from(v.get_i64("w").ok()?).ok()?,
+                        h: u32::try_from(v.get_i64("h").ok()?).ok()?,
+                        url: public_url(config, v.get_str("k").ok()?),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Checks an upload's name and type the same way for `POST /transfer` and `PUT /upload/{name}`:
+/// the name rules, the type (inferred from the name with `INFER_CONTENT_TYPE` when blank), the
+/// format lists and `STRICT_EXTENSION`. Every problem is pushed onto `errors`. Returns the
+/// sanitized name, `None` if it was refused, and the lowercased type.
+fn check_upload(
+    config: &Config,
+    raw_name: &str,
+    declared_type: &str,
+    errors: &mut Vec<AppError>,
+) -> (Option<String>, String) {
+    let name = sanitize_name(raw_name, config).map_err(|e| errors.push(e)).ok();
+
+    let mut content_type = declared_type.trim().to_lowercase();
+    if content_type.is_empty() && config.infer_content_type {
+        match mime::from_filename(raw_name.trim()) {
+            Some(inferred) => content_type = inferred.to_string(),
+            // Already reported, so the format checks are skipped for it
+            None => {
+                errors.push(AppError::BadRequest(
+                    "Missing type and could not infer it from the file name".into(),
+                ));
+                return (name, content_type);
+            }
+        }
+    }
+    if let Err(e) = check_format(config, &content_type) {
+        errors.push(e);
+    }
+    if let Err(e) = check_extension(config, raw_name, &content_type) {
+        errors.push(e);
+    }
+    (name, content_type)
+}
+
+/// Checks the optional metadata both upload routes take, pushing problems onto `errors`.
+/// Returns the trimmed, de-duplicated `acl`.
+fn check_upload_meta(
+    config: &Config,
+    description: &str,
+    tags: &HashMap<String, String>,
+    acl: &[String],
+    expires_in_secs: Option<u64>,
+    errors: &mut Vec<AppError>,
+) -> Vec<String> {
+    if let Err(e) = validate_metadata(config, description, tags) {
+        errors.push(e);
+    }
+    if expires_in_secs.is_some_and(|secs| secs == 0 || secs > MAX_EXPIRES_IN_SECS) {
+        errors.push(AppError::BadRequest(format!(
+            "expires_in_secs must be between 1 and {MAX_EXPIRES_IN_SECS}"
+        )));
+    }
+    validate_acl(acl).map_err(|e| errors.push(e)).unwrap_or_default()
+}
+
+/// Object key for a new upload of `raw_name` (sanitized to `name`), plus the name as sent when
+/// `SAFE_KEYS` or `CONTENT_ADDRESSED` keep it for display and downloads
+fn upload_key(config: &Config, raw_name: &str, name: &str) -> (String, Option<String>) {
+    let orig_name =
+        (config.safe_keys || config.content_addressed).then(|| raw_name.trim().to_string());
+    let key = if config.safe_keys {
+        object_key(config, &safe_key_name(name))
+    } else {
+        object_key(config, name)
+    };
+    (key, orig_name)
+}
+
+/// With `NAME_DEDUP`, the response for an upload whose key is already taken today: the stored
+/// image, marked `existing`, instead of overwriting it
+async fn same_name_image(state: &AppState, key: &str) -> Result<Option<HttpResponse>, AppError> {
+    if !state.config.name_dedup {
+        return Ok(None);
+    }
+    let existing = find_one_image(
+        state,
+        mongodb::bson::doc! { "f": key },
+        mongodb::bson::doc! { "_id": 1 },
+    )
+    .await?;
+    let Some(existing_id) = existing.as_ref().and_then(|d| d.get_str("_id").ok()) else {
+        return Ok(None);
+    };
+    log::info!("Deduped by name: {key} → {existing_id}");
+    Ok(Some(HttpResponse::Ok().json(json!({
+        "ok": OkFlag(true),
+        "id": existing_id,
+        "url": public_url(&state.config, key),
+        "key": key,
+        "existing": 1,
+    }))))
+}
+
+// POST /transfer
+pub async fn create_transfer(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<TransferRequest>,
+) -> Result<HttpResponse, AppError> {
+    let (owner, _slot) = authorize_transfer(&state, &req)?;
+    ensure_upload_window(&state.config)?;
+    // Presigning is local, but the upload it hands out would only fail against a store that's down
+    s3_available(&state)?;
+
+    // Every check runs so the client gets all problems in one response
+    let mut errors = Vec::new();
+
+    let (name, content_type) =
+        check_upload(&state.config, &body.name, &body.content_type, &mut errors);
+    if body.size == 0 {
+        errors.push(AppError::BadRequest("size must be the file size in bytes, greater than 0".into()));
+    } else if body.size > state.config.max_size {
+        errors.push(AppError::LargePayload(format!(
+            "Max {}MB, declared size is {} bytes",
+            state.config.max_size_mb, body.size
+        )));
+    }
+    let description = body.description.trim();
+    let acl = check_upload_meta(
+        &state.config,
+        description,
+        &body.tags,
+        &body.acl,
+        body.expires_in_secs,
+        &mut errors,
+    );
+    let crc32c = match body.crc32c.as_deref() {
+        Some(raw) => parse_crc32c(raw).or_else(|| {
+            errors.push(AppError::BadRequest(
+                "crc32c must be the file's CRC32C as 4 base64-encoded bytes".into(),
+            ));
+            None
+        }),
+        None if state.config.crc32c_checksums => {
+            errors.push(AppError::BadRequest("crc32c is required".into()));
+            None
+        }
+        None => None,
+    };
+
+    let Some(name) = name.filter(|_| errors.is_empty()) else {
+        return Err(AppError::Validation(errors));
+    };
+
+    // The client must PUT with the exact Content-Type that was signed
+    let signed_type = if body.content_type.is_empty() {
+        content_type.as_str()
+    } else {
+        body.content_type.as_str()
+    };
+
+    // A client repeating the exact same request gets the presign it was just given
+    let fingerprint = (state.config.presign_cache_secs > 0 && state.redis.is_some()).then(|| {
+        let ip = net::client_ip(&req, &state.config.trusted_proxies).map(|ip| ip.to_string());
+        let tags: BTreeMap<&String, &String> = body.tags.iter().collect();
+        let request = (
+            ip,
+            &owner,
+            &name,
+            body.size,
+            signed_type,
+            description,
+            tags,
+            &acl,
+            body.expires_in_secs,
+            &crc32c,
+        );
+        hex::encode(Sha256::digest(serde_json::to_vec(&request).unwrap_or_default()))
+    });
+    if let Some(fingerprint) = &fingerprint {
+        if let Some(cached) = cached_presign(&state, fingerprint).await {
+            log::info!("Transfer presign reused for {name}");
+            return Ok(HttpResponse::Ok()
+                .content_type(header::ContentType::json())
+                .body(cached));
+        }
+    }
+
+    let id = gen_id();
+    let (key, orig_name) = upload_key(&state.config, &body.name, &name);
+    if let Some(existing) = same_name_image(&state, &key).await? {
+        return Ok(existing);
+    }
+
+    log::info!("Transfer: {id} → {key}");
+
+    let presign_config = PresigningConfig::builder()
+        .expires_in(Duration::from_secs(300))
+        .build()
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let presigned = state
+        .s3
+        .put_object()
+        .bucket(&state.config.r2_bucket)
+        .key(&key)
+        .content_type(signed_type)
+        .set_cache_control(state.config.object_cache_control.clone())
+        .set_content_disposition(orig_name.as_deref().map(content_disposition))
+        .set_metadata(object_metadata(&state.config))
+        .set_checksum_crc32_c(crc32c.clone())
+        .presigned(presign_config)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload_url = presigned.uri().to_string();
+    // Every signed header must be sent verbatim or R2 rejects the PUT
+    let upload_headers = presigned
+        .headers()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    let pending = PendingTransfer {
+        key: key.clone(),
+        size: body.size,
+        description: description.to_string(),
+        tags: body.tags.clone(),
+        orig_name,
+        acl,
+        owner,
+        expires_in_secs: body.expires_in_secs,
+        crc32c,
+    };
+
+    save_pending(&state, &id, &pending).await?;
+
+    let response = TransferResponse {
+        ok: OkFlag(true),
+        id,
+        upload_url,
+        upload_headers,
+        // A content-addressed upload moves to its hash key on completion, so this one won't last
+        url: (!state.config.content_addressed).then(|| public_url(&state.config, &key)),
+        key,
+    };
+    if let Some(fingerprint) = &fingerprint {
+        if let Ok(json) = serde_json::to_string(&response) {
+            cache_presign(&state, fingerprint, &json).await;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// POST /transfer/{id}/done
+pub async fn complete_transfer(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let (_, _slot) = authorize_transfer(&state, &req)?;
+    let id = path.into_inner();
+
+    let token = match lock_completion(&state, &id).await? {
+        CompletionLock::Held(token) => token,
+        CompletionLock::Recorded => {
+            // Recorded by the completion this one waited for
+            let payload = load_payload(&state, &id).await?;
+            return Ok(HttpResponse::Ok().json(json!({
+                "ok": OkFlag(true),
+                "id": id,
+                "url": payload.url,
+                "key": payload.f,
+            })));
+        }
+    };
+    let result = complete_locked(&state, &id).await;
+    unlock_completion(&state, &id, token).await;
+    result
+}
+
+/// The completion itself, run while holding the completion lock
+async fn complete_locked(state: &AppState, id: &str) -> Result<HttpResponse, AppError> {
+    // A repeat of a recorded completion adds no image, so a full catalog still answers it
+    if let Some(max) = state.config.max_total_images {
+        if !image_recorded(state, id).await? {
+            ensure_capacity(state, max).await?;
+        }
+    }
+
+    let mut pending = load_pending(state, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Transfer expired or not found".into()))?;
+
+    let head = head_uploaded(state, &pending.key, pending.crc32c.is_some()).await?;
+    if let Some(actual) = head.content_length().and_then(|n| u64::try_from(n).ok()) {
+        let checked = reconcile_size(&state.config, id, &mut pending, actual);
+        or_discard(state, id, &pending.key, checked).await?;
+    }
+    if let Some(expected) = &pending.crc32c {
+        let checked = verify_crc32c(id, &pending.key, expected, head.checksum_crc32_c());
+        or_discard(state, id, &pending.key, checked).await?;
+    }
+
+    log::info!("Verified: {id}");
+
+    if state.config.strict_extension {
+        let checked = confirm_extension(state, &pending.key).await;
+        or_discard(state, id, &pending.key, checked).await?;
+    }
+
+    let stored_type = head.content_type().unwrap_or("").to_lowercase();
+    // The pending record is the retry handle: every step up to and including the insert is safe
+    // to repeat, so it is only removed once the image is recorded (or the upload was rejected and
+    // deleted). Until then a failed completion can be retried until the record expires.
+    let delete_token = state.config.delete_tokens.then(gen_delete_token);
+    let done = finalize_upload(
+        state,
+        id,
+        pending,
+        &stored_type,
+        None,
+        delete_token.as_deref().map(auth::hash_delete_token),
+    )
+    .await?;
+    clear_pending(state, id).await;
+
+    // The final location: with `CONTENT_ADDRESSED` the object has moved to its hash key
+    let mut body = json!({ "ok": OkFlag(true), "id": id, "url": done.url, "key": done.key });
+    // A repeat of an already recorded completion can't hand out the original token again
+    if let Some(token) = delete_token.filter(|_| done.recorded) {
+        body["deleteToken"] = token.into();
+    }
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// What `lock_completion` found
+enum CompletionLock {
+    /// This request completes the transfer, holding the lock under this token when one was taken
+    Held(Option<String>),
+    /// The request holding the lock recorded the image while this one waited
+    Recorded,
+}
+
+/// Takes the Redis lock `lock:complete:{id}` so concurrent completions of one transfer run one
+/// at a time. The lock holds a random token per request. While another request holds it this
+/// waits, for at most `COMPLETE_LOCK_WAIT_SECS` before answering `409`. Without Redis or with
+/// `COMPLETE_LOCK=false` there is no lock and duplicates are settled by the insert instead.
+async fn lock_completion(state: &AppState, id: &str) -> Result<CompletionLock, AppError> {
+    let Some(redis) = state.redis.as_ref().filter(|_| state.config.complete_lock) else {
+        return Ok(CompletionLock::Held(None));
+    };
+    let key = state.config.redis_key("lock", &format!("complete:{id}"));
+    // The holder is cancelled at the request timeout, so the lock can't outlive it for long
+    let ttl = i64::try_from(state.config.request_timeout_secs)
+        .unwrap_or(i64::MAX)
+        .saturating_add(1);
+    let redis_err = |e: RedisError| AppError::Internal(format!("Redis: {e}"));
+    let token = hex::encode(rand::random::<[u8; 16]>());
+    let deadline = Instant::now() + Duration::from_secs(COMPLETE_LOCK_WAIT_SECS);
+    let busy = || {
+        AppError::Conflict("Transfer is being completed by another request, retry later".into())
+    };
+
+    loop {
+        let taken: Option<String> = redis
+            .set(&key, token.as_str(), Some(Expiration::EX(ttl)), Some(SetOptions::NX), false)
+            .await
+            .map_err(redis_err)?;
+        if taken.is_some() {
+            return Ok(CompletionLock::Held(Some(token)));
+        }
+        while redis.exists::<i64, _>(&key).await.map_err(redis_err)? > 0 {
+            if Instant::now() >= deadline {
+                return Err(busy());
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(COMPLETE_LOCK_POLL_MS)).await;
+        }
+        if image_recorded(state, id).await? {
+            return Ok(CompletionLock::Recorded);
+        }
+        if Instant::now() >= deadline {
+            return Err(busy());
+        }
+        // The other completion failed; this one takes its turn
+    }
+}
+
+/// Whether an image document exists for `id`
+async fn image_recorded(state: &AppState, id: &str) -> Result<bool, AppError> {
+    let recorded = state
+        .images_for(id)
+        .find_one(mongodb::bson::doc! { "_id": id })
+        .projection(mongodb::bson::doc! { "_id": 1 })
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+    Ok(recorded.is_some())
+}
+
+/// Releases the completion lock if it still holds `token`; a failed delete just leaves it to expire
+async fn unlock_completion(state: &AppState, id: &str, token: Option<String>) {
+    let (Some(redis), Some(token)) = (state.redis.as_ref(), token) else {
+        return;
+    };
+    let key = state.config.redis_key("lock", &format!("complete:{id}"));
+    let released: Result<i64, _> = redis.eval(COMPLETE_UNLOCK_SCRIPT, key, token).await;
+    if let Err(e) = released {
+        log::warn!("Completion lock for {id} left to expire: {e}");
+    }
+}
+
+/// File name for a mirrored object from the last path segment of its source URL
+fn mirror_name(url: &url::Url, config: &Config) -> String {
+    let segment = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|s| urlencoding::decode(s).map_or_else(|_| s.to_string(), |d| d.into_owned()))
+        .unwrap_or_default();
+    sanitize_name(&segment, config).unwrap_or_else(|_| "image".into())
+}
+
+// POST /mirror — copy a remote image into the bucket, once per source URL
+pub async fn mirror_image(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<MirrorRequest>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_TRANSFER_WRITE)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+    ensure_upload_window(&state.config)?;
+    s3_available(&state)?;
+
+    let url = remote::validate_outbound_url(body.url.trim(), state.config.allow_http_outbound)?;
+    let source = url.to_string();
+    let description = body.description.trim();
+    validate_metadata(&state.config, description, &body.tags)?;
+
+    // A source already mirrored resolves to the stored copy
+    let existing = find_one_image(
+        &state,
+        mongodb::bson::doc! { "src": &source },
+        mongodb::bson::doc! { "f": 1 },
+    )
+    .await?;
+    if let Some(existing) = existing {
+        let id = existing.get_str("_id").unwrap_or("");
+        let key = existing.get_str("f").unwrap_or("");
+        return Ok(HttpResponse::Ok().json(json!({
+            "ok": OkFlag(true),
+            "id": id,
+            "url": public_url(&state.config, key),
+            "key": key,
+            "existing": 1,
+        })));
+    }
+
+    if let Some(max) = state.config.max_total_images {
+        ensure_capacity(&state, max).await?;
+    }
+
+    // Keeps the server from being used to flood a third party
+    let _permit = state
+        .outbound
+        .try_acquire()
+        .map_err(|_| AppError::TooManyRequests("Too many remote fetches in flight".into()))?;
+    count_outbound_fetch(&state, url.host_str().unwrap_or_default())?;
+
+    let fetched = remote::fetch(
+        url.clone(),
+        state.config.allow_http_outbound,
+        state.config.max_size,
+        Duration::from_secs(state.config.mirror_timeout_secs),
+    )
+    .await?;
+
+    let content_type = fetched.content_type;
+    if !content_type.starts_with("image/")
+        || !format_allowed(&state.config.allowed_formats, &content_type)
+    {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported file format '{content_type}'. Allowed: {}",
+            state.config.allowed_formats.join(", ")
+        )));
+    }
+    if format_allowed(&state.config.blocked_formats, &content_type) {
+        return Err(format_blocked(&content_type));
+    }
+    // Decodable types must actually be what the server claimed
+    if imaging::is_raster(&content_type) && imaging::sniff(&fetched.bytes) != Some(content_type.as_str()) {
+        return Err(AppError::Unprocessable(format!(
+            "Remote file is not a valid {content_type}"
+        )));
+    }
+
+    let id = gen_id();
+    // The id keeps common names like `image.jpg` from different sources apart
+    let name = mirror_name(&url, &state.config);
+    let key = object_key(&state.config, &format!("{id}-{name}"));
+    let size = fetched.bytes.len() as u64;
+    let crc32c = state
+        .config
+        .crc32c_checksums
+        .then(|| crc32c_header(crc32c::crc32c(&fetched.bytes)));
+
+    log::info!("Mirror: {id} ← {source}");
+
+    state
+        .s3
+        .put_object()
+        .bucket(&state.config.r2_bucket)
+        .key(&key)
+        .content_type(&content_type)
+        .set_cache_control(state.config.object_cache_control.clone())
+        .set_metadata(object_metadata(&state.config))
+        .set_checksum_crc32_c(crc32c.clone())
+        .body(ByteStream::from(fetched.bytes))
+        .send()
+        .await
+        .map_err(|e| s3_error(&state, &e))?;
+    s3_ok(&state);
+
+    let upload = PendingTransfer {
+        key: key.clone(),
+        size,
+        description: description.to_string(),
+        tags: body.into_inner().tags,
+        // A hash key says nothing about the file, so the name is kept for display
+        orig_name: state.config.content_addressed.then_some(name),
+        acl: Vec::new(),
+        owner: Some(key_id),
+        expires_in_secs: None,
+        crc32c,
+    };
+    let done = finalize_upload(&state, &id, upload, &content_type, Some(&source), None).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "ok": OkFlag(true),
+        "id": id,
+        "url": done.url,
+        "key": done.key,
+    })))
+}
+
+// PUT /upload/{name} — single-request upload for clients that can't presign and PUT separately
+pub async fn stream_upload(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<UploadQuery>,
+    mut body: web::Payload,
+) -> Result<HttpResponse, AppError> {
+    let (owner, _slot) = authorize_transfer(&state, &req)?;
+    ensure_upload_window(&state.config)?;
+    s3_available(&state)?;
+
+    let raw_name = path.into_inner();
+    let declared_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or("");
+    let mut errors = Vec::new();
+    let (name, content_type) = check_upload(&state.config, &raw_name, declared_type, &mut errors);
+    // A declared length over the cap is refused before reading; the cap is enforced while reading either way
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(declared) = declared.filter(|&n| n > state.config.max_size) {
+        errors.push(AppError::LargePayload(format!(
+            "Max {}MB, declared size is {declared} bytes",
+            state.config.max_size_mb
+        )));
+    }
+    let query = query.into_inner();
+    let description = query.description.trim();
+    let tags: HashMap<String, String> = match query.tags.as_deref() {
+        Some(raw) => serde_json::from_str(raw).unwrap_or_else(|_| {
+            errors.push(AppError::BadRequest("tags must be a JSON object of strings".into()));
+            HashMap::new()
+        }),
+        None => HashMap::new(),
+    };
+    let acl: Vec<String> = query
+        .acl
+        .as_deref()
+        .filter(|raw| !raw.trim().is_empty())
+        .map(|raw| raw.split(',').map(String::from).collect())
+        .unwrap_or_default();
+    let acl = check_upload_meta(
+        &state.config,
+        description,
+        &tags,
+        &acl,
+        query.expires_in_secs,
+        &mut errors,
+    );
+    let Some(name) = name.filter(|_| errors.is_empty()) else {
+        return Err(AppError::Validation(errors));
+    };
+
+    if let Some(max) = state.config.max_total_images {
+        ensure_capacity(&state, max).await?;
+    }
+
+    let id = gen_id();
+    let (key, orig_name) = upload_key(&state.config, &raw_name, &name);
+    if let Some(existing) = same_name_image(&state, &key).await? {
+        return Ok(existing);
+    }
+
+    log::info!("Upload: {id} → {key}");
+    let (size, crc) = store_body(&state, &key, &content_type, orig_name.as_deref(), &mut body).await?;
+
+    let upload = PendingTransfer {
+        key: key.clone(),
+        size,
+        description: description.to_string(),
+        tags,
+        orig_name,
+        acl,
+        owner,
+        expires_in_secs: query.expires_in_secs,
+        crc32c: state.config.crc32c_checksums.then(|| crc32c_header(crc)),
+    };
+    let delete_token = state.config.delete_tokens.then(gen_delete_token);
+    let done = finalize_upload(
+        &state,
+        &id,
+        upload,
+        &content_type,
+        None,
+        delete_token.as_deref().map(auth::hash_delete_token),
+    )
+    .await?;
+
+    let mut res = json!({
+        "ok": OkFlag(true),
+        "id": id,
+        "url": done.url,
+        "key": done.key,
+    });
+    if let Some(token) = delete_token.filter(|_| done.recorded) {
+        res["deleteToken"] = token.into();
+    }
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// Reads the body into `buf` until it holds a full part or the body ends (`true`).
+/// Fails once more than `MAX_SIZE_MB` has arrived in total.
+async fn fill_part(
+    state: &AppState,
+    body: &mut web::Payload,
+    buf: &mut Vec<u8>,
+    total: &mut u64,
+) -> Result<bool, AppError> {
+    while buf.len() < UPLOAD_PART_BYTES {
+        let Some(chunk) = body
+            .try_next()
+            .await
+            .map_err(|e| AppError::BadRequest(format!("Upload body: {e}")))?
+        else {
+            return Ok(true);
+        };
+        *total += chunk.len() as u64;
+        if *total > state.config.max_size {
+            return Err(AppError::LargePayload(format!(
+                "Max {}MB, upload is larger",
+                state.config.max_size_mb
+            )));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(false)
+}
+
+/// Streams a request body into `key` and returns its size and CRC32C. A body that fits in one
+/// part is a single PUT; anything larger becomes a multipart upload, aborted again if the body
+/// fails, so no more than `UPLOAD_PART_BYTES` of it is held in memory at once.
+async fn store_body(
+    state: &AppState,
+    key: &str,
+    content_type: &str,
+    orig_name: Option<&str>,
+    body: &mut web::Payload,
+) -> Result<(u64, u32), AppError> {
+    let mut total = 0u64;
+    let mut buf = Vec::with_capacity(UPLOAD_PART_BYTES);
+    let ended = fill_part(state, body, &mut buf, &mut total).await?;
+    if buf.is_empty() {
+        return Err(AppError::BadRequest("Upload body is empty".into()));
+    }
+    // Decodable types must actually be what the client claimed
+    if imaging::is_raster(content_type) && imaging::sniff(&buf) != Some(content_type) {
+        return Err(AppError::Unprocessable(format!("Upload is not a valid {content_type}")));
+    }
+    let mut crc = crc32c::crc32c(&buf);
+
+    if ended {
+        state
+            .s3
+            .put_object()
+            .bucket(&state.config.r2_bucket)
+            .key(key)
+            .content_type(content_type)
+            .set_cache_control(state.config.object_cache_control.clone())
+            .set_content_disposition(orig_name.map(content_disposition))
+            .set_metadata(object_metadata(&state.config))
+            .set_checksum_crc32_c(state.config.crc32c_checksums.then(|| crc32c_header(crc)))
+            .body(ByteStream::from(buf))
+            .send()
+            .await
+            .map_err(|e| s3_error(state, &e))?;
+        s3_ok(state);
+        return Ok((total, crc));
+    }
+
+    let upload_id = state
+        .s3
+        .create_multipart_upload()
+        .bucket(&state.config.r2_bucket)
+        .key(key)
+        .content_type(content_type)
+        .set_cache_control(state.config.object_cache_control.clone())
+        .set_content_disposition(orig_name.map(content_disposition))
+        .set_metadata(object_metadata(&state.config))
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?
+        .upload_id
+        .ok_or_else(|| AppError::Internal("S3: multipart upload without an id".into()))?;
+    s3_ok(state);
+
+    let written = async {
+        let mut parts = Vec::new();
+        let mut ended = false;
+        while !buf.is_empty() {
+            let part_number = i32::try_from(parts.len() + 1).unwrap_or(i32::MAX);
+            let part = state
+                .s3
+                .upload_part()
+                .bucket(&state.config.r2_bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(std::mem::take(&mut buf)))
+                .send()
+                .await
+                .map_err(|e| s3_error(state, &e))?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag)
+                    .part_number(part_number)
+                    .build(),
+            );
+            if !ended {
+                ended = fill_part(state, body, &mut buf, &mut total).await?;
+                crc = crc32c::crc32c_append(crc, &buf);
+            }
+        }
+        state
+            .s3
+            .complete_multipart_upload()
+            .bucket(&state.config.r2_bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await
+            .map_err(|e| s3_error(state, &e))
+    }
+    .await;
+
+    if let Err(e) = written {
+        // Unfinished parts are billed until aborted
+        if let Err(abort) = state
+            .s3
+            .abort_multipart_upload()
+            .bucket(&state.config.r2_bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .send()
+            .await
+        {
+            log::warn!("Multipart upload {upload_id} for {key} not aborted: {abort}");
+        }
+        return Err(e);
+    }
+    s3_ok(state);
+    Ok((total, crc))
+}
+
+/// Counts a fetch against `OUTBOUND_HOST_RATE` for the current minute, or 429 when the host
+/// has had its share. Counts are per process, like the concurrency limits.
+fn count_outbound_fetch(state: &AppState, host: &str) -> Result<(), AppError> {
+    let limit = state.config.outbound_host_rate;
+    if limit == 0 {
+        return Ok(());
+    }
+    let (_, now) = now_parts();
+    let window = now - now % 60;
+
+    let mut hosts = state.outbound_hosts.lock().unwrap_or_else(|e| e.into_inner());
+    hosts.retain(|_, (start, _)| *start == window);
+    let (_, count) = hosts.entry(host.to_ascii_lowercase()).or_insert((window, 0));
+    if *count >= limit {
+        return Err(AppError::TooManyRequests(format!(
+            "{host} was already fetched {limit} times this minute"
+        )));
+    }
+    *count += 1;
+    Ok(())
+}
+
+/// Inserts a completed image's document, retrying failures `INSERT_RETRIES` times. A duplicate
+/// `_id` whose stored document has our key means the image is already recorded: `Ok(true)` when
+/// one of our own attempts landed despite a lost acknowledgement, `Ok(false)` when a concurrent
+/// or earlier completion of the same transfer got there first.
+async fn insert_image(
+    state: &AppState,
+    id: &str,
+    key: &str,
+    doc: mongodb::bson::Document,
+) -> Result<bool, mongodb::error::Error> {
+    let mut attempt = 0;
+    loop {
+        match state.images_for(id).insert_one(doc.clone()).await {
+            Ok(_) => return Ok(true),
+            Err(e) if is_duplicate_key(&e) => {
+                let landed = state
+                    .images_for(id)
+                    .find_one(mongodb::bson::doc! { "_id": id, "f": key })
+                    .projection(mongodb::bson::doc! { "_id": 1 })
+                    .await
+                    .is_ok_and(|d| d.is_some());
+                // Otherwise the id is taken by another image and retrying can't help
+                return if landed { Ok(attempt > 0) } else { Err(e) };
+            }
+            Err(e) if attempt >= state.config.insert_retries => return Err(e),
+            Err(e) => log::warn!("Insert of {id} failed, retrying: {e}"),
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(INSERT_RETRY_DELAY_MS << attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Notes an object (and its variants) left in R2 without an image document so it can be cleaned
+/// up later. The transfer stays pending, so a client retry may still complete it: an entry is
+/// only an orphan while no image document has its key.
+async fn record_orphan(state: &AppState, id: &str, key: &str, processed: Option<&Processed>, error: &str) {
+    let variants: Vec<&str> = processed
+        .map(|p| p.variants.iter().map(|(_, _, k)| k.as_str()).collect())
+        .unwrap_or_default();
+    let (_, ts) = now_parts();
+    let entry = mongodb::bson::doc! { "_id": key, "i": id, "v": variants, "t": ts, "e": error };
+    match state
+        .orphans
+        .replace_one(mongodb::bson::doc! { "_id": key }, entry)
+        .upsert(true)
+        .await
+    {
+        Ok(_) => log::error!("Insert of {id} failed, {key} recorded as orphan: {error}"),
+        Err(e) => log::error!("Insert of {id} failed and {key} could not be recorded as orphan: {e}"),
+    }
+}
+
+/// SHA-256 of a stored object, hex-encoded. The body is hashed as it streams in, so large
+/// uploads aren't held in memory.
+async fn hash_object(state: &AppState, key: &str) -> Result<String, AppError> {
+    s3_available(state)?;
+    let object = state
+        .s3
+        .get_object()
+        .bucket(&state.config.r2_bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+
+    let mut hasher = Sha256::new();
+    let mut body = object.body;
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .map_err(|e| AppError::Internal(format!("S3: {e}")))?
+    {
+        hasher.update(&chunk);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// `CONTENT_ADDRESSED` key for an upload: its SHA-256, keeping the uploaded key's extension so
+/// URLs still say what they point at. Uploads with an owner go under a folder hashed from it,
+/// so identical files are only shared within one tenant and keys don't reveal who owns them.
+fn content_key(owner: Option<&str>, uploaded: &str, digest: &str) -> String {
+    let file = uploaded.rsplit('/').next().unwrap_or(uploaded);
+    let name = match file.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() => format!("{digest}.{}", ext.to_lowercase()),
+        _ => digest.to_string(),
+    };
+    match owner {
+        Some(owner) => format!("{}/{name}", hex::encode(&Sha256::digest(owner)[..8])),
+        None => name,
+    }
+}
+
+/// Whether a key was named by `content_key`, and so must keep pointing at the same bytes and
+/// may be shared by several images
+fn is_content_key(key: &str) -> bool {
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    let (folder, file) = key.rsplit_once('/').map_or((None, key), |(f, n)| (Some(f), n));
+    let stem = file.split_once('.').map_or(file, |(stem, _)| stem);
+    is_hex(stem, 64) && folder.is_none_or(|f| is_hex(f, 16))
+}
+
+/// Whether `key` exists in `bucket`. Only a 404 counts as missing; other failures go through
+/// the storage breaker.
+async fn object_exists(state: &AppState, bucket: &str, key: &str) -> Result<bool, AppError> {
+    s3_available(state)?;
+    match state.s3.head_object().bucket(bucket).key(key).send().await {
+        Ok(_) => {
+            s3_ok(state);
+            Ok(true)
+        }
+        Err(e) if e.as_service_error().is_some_and(HeadObjectError::is_not_found) => {
+            s3_ok(state);
+            Ok(false)
+        }
+        Err(e) => Err(s3_error(state, &e)),
+    }
+}
+
+/// Copies `from` to the content-addressed `to` unless it is already there. An existing object
+/// holds the same bytes and may belong to other images, so it is left as it is.
+async fn ensure_copied(state: &AppState, from: &str, to: &str) -> Result<(), AppError> {
+    let bucket = &state.config.r2_bucket;
+    if object_exists(state, bucket, to).await? {
+        return Ok(());
+    }
+    state
+        .s3
+        .copy_object()
+        .bucket(bucket)
+        .copy_source(format!("{bucket}/{}", encode_key(from)))
+        .key(to)
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+    Ok(())
+}
+
+/// Which of `keys` in `bucket` an image other than `ids` still points at. Only content-addressed
+/// keys can be shared, so nothing else is looked up.
+async fn shared_keys(
+    state: &AppState,
+    bucket: &str,
+    keys: &[String],
+    ids: &[String],
+) -> Result<HashSet<String>, AppError> {
+    // Documents in the hot bucket have no `b`
+    let b = if bucket == state.config.r2_bucket {
+        mongodb::bson::Bson::Null
+    } else {
+        bucket.into()
+    };
+    let mut shared = HashSet::new();
+    for key in keys.iter().filter(|k| is_content_key(k)) {
+        let other = find_one_image(
+            state,
+            mongodb::bson::doc! { "f": key, "b": b.clone(), "_id": { "$nin": ids } },
+            mongodb::bson::doc! { "_id": 1 },
+        )
+        .await?;
+        if other.is_some() {
+            shared.insert(key.clone());
+        }
+    }
+    Ok(shared)
+}
+
+/// What `finalize_upload` did with an upload
+struct Finalized {
+    /// Object key the image is stored under
+    key: String,
+    /// Its public URL, as the payload has it
+    url: String,
+    /// `false` when an earlier completion already recorded it
+    recorded: bool,
+}
+
+/// Scans, sanitizes and processes a stored object, then records and caches its document.
+/// Shared by `POST /transfer/{id}/done`, `POST /mirror` and `PUT /upload/{name}`; `source` is
+/// the mirrored URL and `delete_token_hash` is stored as `dt`. With `CONTENT_ADDRESSED` the object is moved to its
+/// hash key, which other images of the same owner with the same bytes share.
+async fn finalize_upload(
+    state: &AppState,
+    id: &str,
+    mut upload: PendingTransfer,
+    stored_type: &str,
+    source: Option<&str>,
+    delete_token_hash: Option<String>,
+) -> Result<Finalized, AppError> {
+    if let Some(addr) = &state.config.clamav_addr {
+        let scanned = scan_upload(state, id, &upload.key, addr).await;
+        or_discard(state, id, &upload.key, scanned).await?;
+    }
+
+    let constrained = state.config.min_width.is_some()
+        || state.config.min_height.is_some()
+        || state.config.aspect_ratio.is_some();
+    if constrained && imaging::is_raster(stored_type) {
+        let checked = check_dimensions(state, id, &upload.key).await;
+        or_discard(state, id, &upload.key, checked).await?;
+    }
+
+    if stored_type == SVG_TYPE {
+        let sanitized = sanitize_svg_upload(state, id, &upload).await;
+        if let Some((size, crc)) = or_discard(state, id, &upload.key, sanitized).await? {
+            upload.size = size;
+            // The client's checksum was for the file before it was cleaned
+            if upload.crc32c.is_some() {
+                upload.crc32c = Some(crc);
+            }
+        }
+    }
+
+    let meta = if state.config.extract_meta {
+        read_meta(state, &upload.key).await
+    } else {
+        None
+    };
+
+    let wants_processing = !state.config.variant_widths.is_empty()
+        || state.config.dominant_color
+        || state.config.phash;
+    let processed = if wants_processing && imaging::is_raster(stored_type) {
+        let processed = process_image(state, id, &state.config.r2_bucket, &upload.key).await;
+        or_discard(state, id, &upload.key, processed).await?
+    } else {
+        None
+    };
+
+    // The key the client uploaded to. It is only removed once the image is recorded under the
+    // hash key, so a failed completion can still be retried from it.
+    let mut uploaded_key = None;
+    if state.config.content_addressed {
+        let digest = hash_object(state, &upload.key).await?;
+        let key = content_key(upload.owner.as_deref(), &upload.key, &digest);
+        ensure_copied(state, &upload.key, &key).await?;
+        uploaded_key = Some(std::mem::replace(&mut upload.key, key));
+    }
+
+    let (_, ts) = now_parts();
+    let f = upload.key;
+
+    // Convert to MB and round to 2 decimals using safe f64 conversion scaling
+    #[allow(clippy::cast_precision_loss)]
+    let s_mb = upload.size as f64 / 1_048_576.0;
+    let s = (s_mb * 100.0).round() / 100.0;
+
+    let url = image_url(
+        &state.config,
+        &f,
+        processed.as_ref().map(|p| p.width),
+        processed.as_ref().map(|p| p.height),
+    );
+    let (cdn_url, origin_url) = cdn_and_origin(&state.config, &f);
+
+    let mut doc = mongodb::bson::doc! {
+        "_id": id,
+        "f": &f,
+        "s": s,
+        "t": ts,
+    };
+    // Both start empty; reads treat a missing field as "", so `COMPACT_DOCS` leaves them out
+    if !state.config.compact_docs {
+        doc.insert("d", "");
+        doc.insert("P", "");
+    }
+    // `d` belongs to the Drive sync script, so the client description gets its own field
+    if !upload.description.is_empty() {
+        doc.insert("desc", &upload.description);
+    }
+    if let Some(orig_name) = &upload.orig_name {
+        doc.insert("orig_name", orig_name);
+    }
+    // Only stored when set, so "no `acl` field" is what marks an image public
+    if !upload.acl.is_empty() {
+        doc.insert("acl", &upload.acl);
+    }
+    if let Some(owner) = &upload.owner {
+        doc.insert("o", owner);
+    }
+    let lifetime = upload
+        .expires_in_secs
+        .or_else(|| default_retention(&state.config, upload.owner.as_deref(), stored_type));
+    let exp = lifetime.map(|secs| ts.saturating_add_unsigned(secs));
+    if let Some(exp) = exp {
+        doc.insert("exp", exp);
+    }
+    if let Some(crc) = &upload.crc32c {
+        doc.insert("crc", crc);
+    }
+    if let Some(source) = source {
+        doc.insert("src", source);
+    }
+    if let Some(hash) = delete_token_hash {
+        doc.insert("dt", hash);
+    }
+    if let Some(meta) = &meta {
+        let meta_doc =
+            mongodb::bson::to_document(meta).map_err(|e| AppError::Internal(e.to_string()))?;
+        doc.insert("meta", meta_doc);
+    }
+    if !upload.tags.is_empty() {
+        let tags: mongodb::bson::Document = upload
+            .tags
+            .iter()
+            .map(|(k, v)| (k.clone(), mongodb::bson::Bson::String(v.clone())))
+            .collect();
+        doc.insert("tags", tags);
+    }
+    if let Some(processed) = &processed {
+        doc.extend(processed.fields());
+    }
+
+    let recorded = match insert_image(state, id, &f, doc).await {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            record_orphan(state, id, &f, processed.as_ref(), &e.to_string()).await;
+            return Err(AppError::Internal(format!("MongoDB: {e}")));
+        }
+    };
+    if let Some(uploaded) = &uploaded_key {
+        // Deleting another image that shared the hash key may have removed it before this
+        // document referenced it; from here on deletes see this image and keep the object
+        ensure_copied(state, uploaded, &f).await?;
+        if let Err(e) = delete_object(state, &state.config.r2_bucket, uploaded).await {
+            log::warn!("Content-addressed move left orphan {uploaded}: {e}");
+        }
+    }
+    if !recorded {
+        log::info!("Already completed: {id}");
+        return Ok(Finalized {
+            key: f,
+            url,
+            recorded: false,
+        });
+    }
+
+    log::info!("Saved: {id}");
+
+    // No subscribers is the normal case, so a send error is ignored
+    let _ = state.events.send(TransferEvent {
+        id: id.to_string(),
+        key: f.clone(),
+        size: upload.size,
+        owner: upload.owner.clone(),
+        acl: upload.acl.clone(),
+    });
+
+    // Cache internal payload JSON (without cache indicator yet)
+    let internal_payload = ImageResponsePayload {
+        url: url.clone(),
+        cdn_url,
+        origin_url,
+        f: f.clone(),
+        s,
+        t: ts,
+        d: String::new(),
+        p: String::new(),
+        c: None,
+        meta,
+        w: processed.as_ref().map(|p| p.width),
+        h: processed.as_ref().map(|p| p.height),
+        color: processed.as_ref().and_then(|p| p.color.clone()),
+        variants: processed
+            .map(|p| {
+                p.variants
+                    .into_iter()
+                    .map(|(w, h, k)| ImageVariant {
+                        w,
+                        h,
+                        url: public_url(&state.config, &k),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        webp: None,
+        desc: Some(upload.description).filter(|d| !d.is_empty()),
+        orig_name: upload.orig_name,
+        acl: upload.acl,
+        exp,
+        b: None,
+        tags: upload.tags,
+    };
+
+    // A lookup racing the upload may have left a negative-cache marker for this id
+    cache_del(state, &[id.to_string()]).await;
+
+    if let Ok(json) = serde_json::to_string(&internal_payload) {
+        cache_set(state, id, &json).await;
+    }
+
+    Ok(Finalized {
+        key: f,
+        url,
+        recorded: true,
+    })
+}
+
+/// Builds the client payload from a stored image document, tolerating missing fields
+#[allow(clippy::many_single_char_names)]
+fn payload_from_doc(config: &Config, doc: &mongodb::bson::Document) -> ImageResponsePayload {
+    let f = doc.get_str("f").unwrap_or("").to_string();
+    let b = doc.get_str("b").ok().map(String::from);
+    let s = doc.get_f64("s").unwrap_or(0.0);
+    let t = doc.get_i64("t").unwrap_or(0);
+    let d = doc.get_str("d").unwrap_or("").to_string();
+    let p = doc.get_str("P").unwrap_or("").to_string();
+    let meta = doc
+        .get_document("meta")
+        .ok()
+        .and_then(|m| mongodb::bson::from_document::<ImageMeta>(m.clone()).ok());
+    let tags = doc
+        .get_document("tags")
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let w = doc.get_i64("w").ok().and_then(|w| u32::try_from(w).ok());
+    let h = doc.get_i64("h").ok().and_then(|h| u32::try_from(h).ok());
+    let (url, cdn_url, origin_url) = if b.is_some() {
+        (cold_url(config, &f), None, None)
+    } else {
+        let (cdn_url, origin_url) = cdn_and_origin(config, &f);
+        (image_url(config, &f, w, h), cdn_url, origin_url)
+    };
+
+    ImageResponsePayload {
+        url,
+        cdn_url,
+        origin_url,
+        f,
+        s,
+        t,
+        d,
+        p,
+        c: None,
+        meta,
+        w,
+        h,
+        color: doc.get_str("color").ok().map(String::from),
+        desc: doc.get_str("desc").ok().map(String::from),
+        orig_name: doc.get_str("orig_name").ok().map(String::from),
+        variants: doc_variants(config, doc),
+        webp: doc.get_str("webp").ok().map(|k| public_url(config, k)),
+        acl: doc
+            .get_array("acl")
+            .map(|acl| acl.iter().filter_map(|id| id.as_str().map(String::from)).collect())
+            .unwrap_or_default(),
+        exp: doc.get_i64("exp").ok(),
+        b,
+        tags,
+    }
+}
+
+/// Client-facing JSON for a payload: terse keys by default, descriptive ones with `VERBOSE_KEYS`
+fn client_payload(config: &Config, payload: &ImageResponsePayload) -> Result<serde_json::Value, AppError> {
+    let mut value = if config.verbose_keys {
+        serde_json::to_value(payload.verbose())
+    } else {
+        serde_json::to_value(payload)
+    }
+    .map_err(|e| AppError::Internal(e.to_string()))?;
+    if let Some(fields) = value.as_object_mut() {
+        // Who an image is shared with is not for its viewers to see
+        fields.remove("acl");
+        fields.remove("b");
+        // Derived on the way out rather than cached, so toggling `ISO_TIMESTAMPS` needs no cache flush
+        if config.iso_timestamps {
+            fields.insert("iso_time".into(), rfc3339(payload.t).into());
+        }
+    }
+    Ok(value)
+}
+
+/// Resolves an image payload from the Redis cache, falling back to MongoDB (and re-caching it for 24h).
+/// Cache hits come back with `c = Some(1)`.
+async fn load_payload(state: &AppState, id: &str) -> Result<ImageResponsePayload, AppError> {
+    // Check Redis cache (stores internal payload JSON)
+    if let Some(cached_json) = cache_get(state, id).await {
+        if let Ok(mut payload_obj) = serde_json::from_str::<ImageResponsePayload>(&cached_json) {
+            payload_obj.c = Some(1); // Set cache flag to true
+            return Ok(payload_obj);
+        }
+    }
+
+    if miss_cached(state, id).await {
+        return Err(AppError::NotFound("Image not found".into()));
+    }
+
+    let Some(doc) = find_image_doc(state, id).await? else {
+        cache_miss(state, id).await;
+        return Err(AppError::NotFound("Image not found".into()));
+    };
+
+    let payload_obj = payload_from_doc(&state.config, &doc);
+
+    // Cache internal payload JSON (24h)
+    if let Ok(json) = serde_json::to_string(&payload_obj) {
+        cache_set(state, id, &json).await;
+    }
+
+    Ok(payload_obj)
+}
+
+/// Records that an image was served by bumping its `la` field, off the request path.
+/// A Redis marker keeps it to one write per image per hour; without Redis the filter alone throttles.
+fn touch_last_access(state: &web::Data<AppState>, id: &str) {
+    let state = state.clone();
+    let id = id.to_string();
+    actix_web::rt::spawn(async move {
+        if let Some(redis) = &state.redis {
+            let fresh: Result<Option<String>, _> = redis
+                .set(
+                    state.config.redis_key("la", &id),
+                    "1",
+                    Some(Expiration::EX(ACCESS_WRITE_INTERVAL_SECS)),
+                    Some(SetOptions::NX),
+                    false,
+                )
+                .await;
+            // Marker already present: written within the last hour
+            if matches!(fresh, Ok(None)) {
+                return;
+            }
+        }
+
+        let (_, now) = now_parts();
+        if let Err(e) = state
+            .images_for(&id)
+            .update_one(
+                mongodb::bson::doc! {
+                    "_id": &id,
+                    "$or": [
+                        { "la": { "$exists": false } },
+                        { "la": { "$lt": now - ACCESS_WRITE_INTERVAL_SECS } },
+                    ],
+                },
+                mongodb::bson::doc! { "$set": { "la": now } },
+            )
+            .await
+        {
+            log::warn!("Last-access update failed for {id}: {e}");
+        }
+    });
+}
+
+/// Appends `{ i, t, ip, ua }` to the access log without holding up the response
+fn log_access(state: &web::Data<AppState>, req: &HttpRequest, id: &str) {
+    let (_, ts) = now_parts();
+    let ip = net::client_ip(req, &state.config.trusted_proxies).map(|ip| ip.to_string());
+    let ua = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|ua| ua.chars().take(MAX_LOGGED_UA_LEN).collect::<String>());
+    let entry = mongodb::bson::doc! { "i": id, "t": ts, "ip": ip, "ua": ua };
+
+    let state = state.clone();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = state.access_log.insert_one(entry).await {
+            log::warn!("Access log write failed: {e}");
+        }
+    });
+}
+
+/// True when the client's preferred media type is an image (e.g. a browser `<img>` fetch)
+fn prefers_image(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .is_some_and(|first| first.trim().to_lowercase().starts_with("image/"))
+}
+
+/// Whether `Accept` explicitly lists `image/webp` with a non-zero quality
+fn accepts_webp(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| {
+            accept.split(',').any(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                parts.next().is_some_and(|ty| ty.eq_ignore_ascii_case("image/webp"))
+                    && !parts.any(|param| {
+                        param
+                            .strip_prefix("q=")
+                            .and_then(|q| q.trim().parse::<f32>().ok())
+                            .is_some_and(|q| q <= 0.0)
+                    })
+            })
+        })
+}
+
+/// Public URL of the image's full-size WebP copy for `WEBP_NEGOTIATION`, rendering it under
+/// `variants/{id}/full.webp` and recording it on the document the first time it's asked for.
+/// Only JPEG and PNG originals are converted; `None` (serve the original) for anything else or
+/// when rendering fails.
+async fn webp_url(state: &AppState, id: &str, payload: &ImageResponsePayload) -> Option<String> {
+    if let Some(url) = &payload.webp {
+        return Some(url.clone());
+    }
+    if !matches!(mime::from_filename(&payload.f), Some("image/jpeg" | "image/png")) {
+        return None;
+    }
+
+    let permit = Arc::clone(&state.processing).acquire_owned().await.ok()?;
+    let bucket = payload.b.as_deref().unwrap_or(&state.config.r2_bucket);
+    let bytes = match fetch_object(state, bucket, &payload.f, None).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::warn!("WebP copy skipped for {id}: {e}");
+            return None;
+        }
+    };
+    let max_decode = state.config.max_decode_bytes;
+    let quality = state.config.variant_quality;
+    let encoded = match render_blocking(state, permit, move || {
+        imaging::decode(&bytes, max_decode).map(|img| imaging::encode_webp(&img, quality))
+    })
+    .await
+    {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            log::warn!("WebP copy skipped for {id}: {e}");
+            return None;
+        }
+    };
+
+    let key = format!("variants/{id}/full.webp");
+    if let Err(e) = put_webp(state, &key, encoded).await {
+        log::warn!("WebP copy upload failed for {key}: {e}");
+        return None;
+    }
+    // Concurrent first requests may each render it; they write the same key, so the last one wins
+    if let Err(e) = state
+        .images_for(id)
+        .update_one(
+            mongodb::bson::doc! { "_id": id },
+            mongodb::bson::doc! { "$set": { "webp": &key } },
+        )
+        .await
+    {
+        log::warn!("WebP copy not recorded for {id}: {e}");
+        return None;
+    }
+    cache_del(state, &[id.to_string()]).await;
+    Some(public_url(&state.config, &key))
+}
+
+/// The object as a `data:` URI for `?inline=1`. Objects over `MAX_INLINE_BYTES` are refused:
+/// the stored size rules out most without a fetch, and the ranged GET reads at most one byte
+/// past the limit to catch the rest.
+async fn inline_data(state: &AppState, payload: &ImageResponsePayload) -> Result<String, AppError> {
+    let max = state.config.max_inline_bytes;
+    if max == 0 {
+        return Err(AppError::BadRequest("Inline images are disabled".into()));
+    }
+    let too_large =
+        || AppError::Unprocessable(format!("Image exceeds {max} bytes and can't be inlined"));
+    // `s` is in MB rounded to 2 decimals, so allow for the rounding before fetching
+    #[allow(clippy::cast_precision_loss)]
+    if (payload.s - 0.005) * 1_048_576.0 > max as f64 {
+        return Err(too_large());
+    }
+    let bucket = payload.b.as_deref().unwrap_or(&state.config.r2_bucket);
+    let bytes = fetch_object(state, bucket, &payload.f, Some(max + 1)).await?;
+    if bytes.len() as u64 > max {
+        return Err(too_large());
+    }
+    let content_type = mime::from_filename(&payload.f).unwrap_or("application/octet-stream");
+    Ok(format!("data:{content_type};base64,{}", BASE64.encode(bytes)))
+}
+
+// GET /i/{id}
+pub async fn get_image(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ImageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let wants_image = query.redirect() || prefers_image(&req);
+
+    let loaded = load_payload(&state, &id)
+        .await
+        .and_then(|payload_obj| ensure_published(&state.config, payload_obj));
+
+    let payload_obj = match loaded {
+        Ok(payload_obj) => payload_obj,
+        // `<img>` tags can't render a JSON 404, so image clients may get a placeholder instead
+        Err(AppError::NotFound(msg)) if wants_image => {
+            return not_found_image(&state.config).ok_or(AppError::NotFound(msg));
+        }
+        Err(e) => return Err(e),
+    };
+    ensure_allowed(&state, &req, &payload_obj)?;
+
+    if state.config.track_last_access {
+        touch_last_access(&state, &id);
+    }
+    if state.config.access_log {
+        log_access(&state, &req, &id);
+    }
+
+    // Redirect mode: send embeds straight to the object instead of the encrypted metadata
+    if wants_image {
+        let mut redirect = HttpResponse::Found();
+        let mut location = None;
+        if state.config.webp_negotiation {
+            // The target depends on `Accept`, so shared caches must key on it
+            redirect.insert_header((header::VARY, "Accept"));
+            if accepts_webp(&req) {
+                location = webp_url(&state, &id, &payload_obj).await;
+            }
+        }
+        let location = location.unwrap_or_else(|| payload_obj.url.clone());
+        let res = image_response(
+            &state.config,
+            redirect.insert_header((header::LOCATION, location)).finish(),
+        );
+        return Ok(cache_status(&state.config, private_response(res, &payload_obj), &payload_obj));
+    }
+
+    let mut payload = client_payload(&state.config, &payload_obj)?;
+    if query.inline() {
+        let data = inline_data(&state, &payload_obj).await?;
+        if let Some(fields) = payload.as_object_mut() {
+            fields.insert("data".into(), data.into());
+        }
+    }
+    if !state.config.obfuscate {
+        let res = HttpResponse::Ok().json(PlainResponse {
+            ok: OkFlag(true),
+            payload,
+        });
+        return Ok(cache_status(&state.config, private_response(res, &payload_obj), &payload_obj));
+    }
+
+    let final_json = payload.to_string();
+    let compressed = state.config.compress_payload;
+    let plaintext = if compressed {
+        gzip(&final_json)?
+    } else {
+        final_json.into_bytes()
+    };
+    let (key_id, key) = payload_key(&state.config, &req)?;
+    let encrypted_hex = encrypt_payload(&plaintext, key, key_id, state.config.payload_versioned)?;
+
+    let mut res = private_response(
+        HttpResponse::Ok().json(ObfuscatedResponse {
+            ok: OkFlag(true),
+            len: encrypted_hex.len() / 2,
+            payload: encrypted_hex,
+            enc: "hex",
+            compressed,
+        }),
+        &payload_obj,
+    );
+    // Another caller would get a payload under a different key
+    if key_id.is_some() {
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("private, no-store"),
+        );
+    }
+    Ok(cache_status(&state.config, res, &payload_obj))
+}
+
+// HEAD /i/{id} — existence and metadata as headers, without building or encrypting the payload
+pub async fn head_image(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let payload_obj = ensure_published(&state.config, load_payload(&state, &id).await?)?;
+    ensure_allowed(&state, &req, &payload_obj)?;
+
+    // Changes whenever the object behind the id does (rename, re-upload under a rotated id)
+    let digest = Sha256::digest(format!("{id}\n{}\n{}", payload_obj.f, payload_obj.t));
+    let etag = format!("\"{}\"", hex::encode(&digest[..8]));
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header((header::ETAG, etag))
+        .insert_header(("X-Image-Size", payload_obj.s.to_string()))
+        .insert_header(("X-Image-Timestamp", payload_obj.t.to_string()));
+    if let Some(content_type) = mime::from_filename(&payload_obj.f) {
+        res.insert_header(("X-Image-Type", content_type));
+    }
+    Ok(private_response(res.finish(), &payload_obj))
+}
+
+/// Parses `?fields=` into the MongoDB projection and the payload keys to keep (in the configured
+/// key scheme). Either naming scheme is accepted; anything outside `LIST_FIELDS` is rejected.
+fn list_projection(
+    config: &Config,
+    fields: &str,
+) -> Result<(mongodb::bson::Document, HashSet<&'static str>), AppError> {
+    let mut projection = mongodb::bson::doc! { "_id": 1 };
+    let mut keep = HashSet::new();
+    for name in fields.split(',').map(str::trim).filter(|n| !n.is_empty() && *n != "id") {
+        let (terse, verbose, stored) = LIST_FIELDS
+            .iter()
+            .find(|(terse, verbose, _)| name == *terse || name == *verbose)
+            .ok_or_else(|| AppError::BadRequest(format!("Unknown field '{name}'")))?;
+        keep.insert(if config.verbose_keys { *verbose } else { *terse });
+        for field in *stored {
+            projection.insert(*field, 1);
+        }
+    }
+    Ok((projection, keep))
+}
+
+// GET /i
+pub async fn list_images(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_READ)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    let mut filter = visible_to(&key_id);
+    if let Some(tag) = &query.tag {
+        let (k, v) = tag
+            .split_once(':')
+            .ok_or_else(|| AppError::BadRequest("tag filter must be key:value".into()))?;
+        if k.is_empty() || k.contains('.') || k.starts_with('$') {
+            return Err(AppError::BadRequest("Invalid tag key".into()));
+        }
+        filter.insert(format!("tags.{k}"), v);
+    }
+    if let Some(after) = &query.after {
+        filter.insert("_id", mongodb::bson::doc! { "$gt": after });
+    }
+    let selected = query
+        .fields
+        .as_deref()
+        .map(|fields| list_projection(&state.config, fields))
+        .transpose()?;
+
+    let projection = selected.as_ref().map(|(projection, _)| projection.clone());
+    let docs = find_images(&state, filter, projection, ("_id", 1), i64::from(limit)).await?;
+
+    let items = docs
+        .iter()
+        .map(|doc| {
+            let mut payload = client_payload(&state.config, &payload_from_doc(&state.config, doc))?;
+            if let (Some((_, keep)), Some(fields)) = (&selected, payload.as_object_mut()) {
+                fields.retain(|k, _| keep.contains(k.as_str()));
+            }
+            Ok(ListItem {
+                id: doc.get_str("_id").unwrap_or("").to_string(),
+                payload,
+            })
+        })
+        .collect::<Result<Vec<_>, AppError>>()?;
+
+    // A full page means there may be more; the last id is the cursor for `after`
+    let next = (items.len() == limit as usize)
+        .then(|| items.last().map(|i| i.id.clone()))
+        .flatten();
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "items": items, "next": next })))
+}
+
+// GET /admin/stale — tiering candidates: images not served in `days` (or never, if older than that)
+pub async fn stale_images(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<StaleQuery>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_READ)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let (_, now) = now_parts();
+    let cutoff = now - i64::from(query.days) * 86400;
+
+    // Like the other listings, images with an `acl` only show up for callers it lists
+    let mut filter = mongodb::bson::doc! {
+        "$and": [
+            {
+                "$or": [
+                    { "la": { "$lt": cutoff } },
+                    { "la": { "$exists": false }, "t": { "$lt": cutoff } },
+                ],
+            },
+            visible_to(&key_id),
+        ],
+    };
+    if let Some(after) = &query.after {
+        filter.insert("_id", mongodb::bson::doc! { "$gt": after });
+    }
+
+    let docs = find_images(
+        &state,
+        filter,
+        Some(mongodb::bson::doc! { "f": 1, "s": 1, "t": 1, "la": 1 }),
+        ("_id", 1),
+        i64::from(limit),
+    )
+    .await?;
+
+    let items: Vec<_> = docs
+        .iter()
+        .map(|doc| {
+            json!({
+                "id": doc.get_str("_id").unwrap_or(""),
+                "f": doc.get_str("f").unwrap_or(""),
+                "s": doc.get_f64("s").unwrap_or(0.0),
+                "t": doc.get_i64("t").unwrap_or(0),
+                "la": doc.get_i64("la").ok(),
+            })
+        })
+        .collect();
+
+    let next = (items.len() == limit as usize)
+        .then(|| docs.last().and_then(|d| d.get_str("_id").ok()))
+        .flatten();
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "items": items, "next": next })))
+}
+
+// GET /i/{id}/similar — near-duplicates by perceptual hash
+pub async fn similar_images(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<SimilarQuery>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_READ)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+    let id = path.into_inner();
+    let max_distance = query
+        .distance
+        .unwrap_or(DEFAULT_SIMILAR_DISTANCE)
+        .min(MAX_SIMILAR_DISTANCE);
+
+    let mut source = visible_to(&key_id);
+    source.insert("_id", &id);
+    let doc = state
+        .images_for(&id)
+        .find_one(source)
+        .projection(mongodb::bson::doc! { "phash": 1 })
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
+        .ok_or_else(|| AppError::NotFound("Image not found".into()))?;
+    let phash = doc
+        .get_str("phash")
+        .map_err(|_| AppError::Unprocessable("Image has no perceptual hash".into()))?
+        .to_string();
+
+    // Bounded scan of the most recent hashed images; Hamming distance isn't indexable in Mongo
+    let mut filter = visible_to(&key_id);
+    filter.insert("_id", mongodb::bson::doc! { "$ne": &id });
+    filter.insert("phash", mongodb::bson::doc! { "$exists": true });
+    let candidates = find_images(
+        &state,
+        filter,
+        Some(mongodb::bson::doc! { "f": 1, "b": 1, "phash": 1, "t": 1 }),
+        ("t", -1),
+        MAX_SIMILAR_SCAN,
+    )
+    .await?;
+
+    let mut matches: Vec<(u32, &mongodb::bson::Document)> = candidates
+        .iter()
+        .filter_map(|d| {
+            let distance = imaging::hash_distance(&phash, d.get_str("phash").ok()?)?;
+            (distance <= max_distance).then_some((distance, d))
+        })
+        .collect();
+    matches.sort_unstable_by_key(|(distance, _)| *distance);
+    matches.truncate(MAX_SIMILAR_RESULTS);
+
+    let items: Vec<_> = matches
+        .into_iter()
+        .filter_map(|(distance, d)| {
+            let f = d.get_str("f").ok()?;
+            // Originals moved to `R2_COLD_BUCKET` are served from its domain
+            let url = if d.get_str("b").is_ok() {
+                cold_url(&state.config, f)
+            } else {
+                public_url(&state.config, f)
+            };
+            Some(json!({ "id": d.get_str("_id").ok()?, "distance": distance, "url": url }))
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "items": items })))
+}
+
+/// Every R2 key an image document owns: the original first, then its variants
+fn owned_keys(doc: &mongodb::bson::Document) -> Option<Vec<String>> {
+    let mut keys = vec![doc.get_str("f").ok()?.to_string()];
+    if let Ok(variants) = doc.get_array("variants") {
+        keys.extend(
+            variants
+                .iter()
+                .filter_map(|v| v.as_document()?.get_str("k").ok())
+                .map(String::from),
+        );
+    }
+    if let Ok(webp) = doc.get_str("webp") {
+        keys.push(webp.to_string());
+    }
+    Some(keys)
+}
+
+/// `owned_keys` grouped by bucket: the original may have moved to `R2_COLD_BUCKET` (`b`), while
+/// variants and the WebP copy always stay in `R2_BUCKET`
+fn owned_objects(
+    config: &Config,
+    doc: &mongodb::bson::Document,
+) -> Option<BTreeMap<String, Vec<String>>> {
+    let original_bucket = doc.get_str("b").unwrap_or(&config.r2_bucket);
+    let mut by_bucket: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (i, key) in owned_keys(doc)?.into_iter().enumerate() {
+        let bucket = if i == 0 { original_bucket } else { &config.r2_bucket };
+        by_bucket.entry(bucket.to_string()).or_default().push(key);
+    }
+    Some(by_bucket)
+}
+
+/// Deletes an image's stored objects, then its document and cache entry. The document stays
+/// while anything is still in storage so the delete can be retried. A content-addressed
+/// original that another image also points at is left in place.
+async fn remove_image(
+    state: &AppState,
+    id: &str,
+    doc: &mongodb::bson::Document,
+) -> Result<(), AppError> {
+    for (bucket, mut keys) in owned_objects(&state.config, doc).unwrap_or_default() {
+        let shared = shared_keys(state, &bucket, &keys, &[id.to_string()]).await?;
+        keys.retain(|k| !shared.contains(k));
+        if keys.is_empty() {
+            continue;
+        }
+        let objects = keys
+            .iter()
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        let delete = Delete::builder()
+            .set_objects(Some(objects))
+            .quiet(true)
+            .build()
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        let out = state
+            .s3
+            .delete_objects()
+            .bucket(bucket)
+            .delete(delete)
+            .send()
+            .await
+            .map_err(|e| s3_error(state, &e))?;
+        s3_ok(state);
+        if let Some(failed) = out.errors().first() {
+            return Err(AppError::Internal(format!(
+                "S3: could not delete {}",
+                failed.key().unwrap_or("object")
+            )));
+        }
+    }
+
+    state
+        .images_for(id)
+        .delete_one(mongodb::bson::doc! { "_id": id })
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+    cache_del(state, &[id.to_string()]).await;
+    Ok(())
+}
+
+/// Removes images whose `exp` has passed, a batch at a time, until none are left or a batch
+/// makes no progress. Run periodically from `main`; failures are logged and retried next time.
+pub async fn sweep_expired(state: &AppState) {
+    let mut removed = 0usize;
+    loop {
+        let (_, now) = now_parts();
+        let batch = match find_images(
+            state,
+            mongodb::bson::doc! { "exp": { "$lte": now } },
+            Some(mongodb::bson::doc! { "f": 1, "b": 1, "variants.k": 1, "webp": 1 }),
+            ("_id", 1),
+            EXPIRY_SWEEP_BATCH,
+        )
+        .await
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                log::warn!("⚠ Expiry sweep failed: {e}");
+                break;
+            }
+        };
+        let mut progressed = false;
+        for doc in &batch {
+            let Ok(id) = doc.get_str("_id") else {
+                continue;
+            };
+            match remove_image(state, id, doc).await {
+                Ok(()) => {
+                    removed += 1;
+                    progressed = true;
+                }
+                Err(e) => log::warn!("⚠ Could not remove expired {id}: {e}"),
+            }
+        }
+        if !progressed || (batch.len() as i64) < EXPIRY_SWEEP_BATCH {
+            break;
+        }
+    }
+    if removed > 0 {
+        log::info!("Expiry sweep removed {removed} image(s)");
+    }
+}
+
+/// Moves one original to the cold bucket: copy, repoint the document, then delete the hot copy.
+/// The document update only matches while `f` is unchanged and it's still hot, so a concurrent
+/// rename or delete wins and the cold copy is removed again.
+async fn migrate_to_cold(
+    state: &AppState,
+    cold: &str,
+    id: &str,
+    key: &str,
+) -> Result<bool, AppError> {
+    let hot = &state.config.r2_bucket;
+    state
+        .s3
+        .copy_object()
+        .bucket(cold)
+        .copy_source(format!("{hot}/{}", encode_key(key)))
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| s3_error(state, &e))?;
+    s3_ok(state);
+
+    let moved = match state
+        .images_for(id)
+        .update_one(
+            mongodb::bson::doc! { "_id": id, "f": key, "b": null },
+            mongodb::bson::doc! { "$set": { "b": cold } },
+        )
+        .await
+    {
+        Ok(res) => res.matched_count > 0,
+        Err(e) => {
+            let _ = state.s3.delete_object().bucket(cold).key(key).send().await;
+            return Err(AppError::Internal(format!("MongoDB: {e}")));
+        }
+    };
+    // Whichever copy the document doesn't point at is removed, unless another image with the
+    // same content-addressed key still uses it
+    let (bucket, leftover) = if moved { (hot.as_str(), "hot") } else { (cold, "cold") };
+    let shared = shared_keys(state, bucket, &[key.to_string()], &[id.to_string()]).await?;
+    if shared.is_empty() {
+        if let Err(e) = state.s3.delete_object().bucket(bucket).key(key).send().await {
+            log::warn!("Cold migration left orphan {leftover} copy of {key}: {e}");
+        }
+    }
+    if moved {
+        cache_del(state, &[id.to_string()]).await;
+    }
+    Ok(moved)
+}
+
+/// Moves originals older than `COLD_AFTER_DAYS` from `R2_BUCKET` to `R2_COLD_BUCKET`, a batch at
+/// a time, until none are left or a batch makes no progress. Run periodically from `main`;
+/// failures are logged and retried next time.
+pub async fn migrate_cold(state: &AppState) {
+    let Some(cold) = &state.config.r2_cold_bucket else {
+        return;
+    };
+    let mut moved = 0usize;
+    loop {
+        if s3_available(state).is_err() {
+            break;
+        }
+        let (_, now) = now_parts();
+        let cutoff = now - i64::from(state.config.cold_after_days) * 86_400;
+        let batch = match find_images(
+            state,
+            mongodb::bson::doc! { "b": null, "t": { "$lte": cutoff } },
+            Some(mongodb::bson::doc! { "f": 1 }),
+            ("t", 1),
+            COLD_MIGRATION_BATCH,
+        )
+        .await
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                log::warn!("⚠ Cold migration failed: {e}");
+                break;
+            }
+        };
+        let mut progressed = false;
+        for doc in &batch {
+            let (Ok(id), Ok(key)) = (doc.get_str("_id"), doc.get_str("f")) else {
+                continue;
+            };
+            match migrate_to_cold(state, cold, id, key).await {
+                Ok(true) => {
+                    moved += 1;
+                    progressed = true;
+                }
+                Ok(false) => {}
+                Err(e) => log::warn!("⚠ Could not move {id} to cold storage: {e}"),
+            }
+        }
+        if !progressed || (batch.len() as i64) < COLD_MIGRATION_BATCH {
+            break;
+        }
+    }
+    if moved > 0 {
+        log::info!("Cold migration moved {moved} image(s) to {cold}");
+    }
+}
+
+// DELETE /i/{id} — with an API key, or with the `X-Delete-Token` handed out at completion
+pub async fn delete_image(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let by_token = state.config.delete_tokens && req.headers().contains_key(auth::DELETE_TOKEN_HEADER);
+    let (caller, _slot) = if by_token {
+        ("delete token".to_string(), None)
+    } else {
+        let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_DELETE)?;
+        let slot = claim_key_slot(&state, &key_id)?;
+        (key_id, slot)
+    };
+    s3_available(&state)?;
+
+    let doc = state
+        .images_for(&id)
+        .find_one(mongodb::bson::doc! { "_id": &id })
+        .projection(mongodb::bson::doc! { "f": 1, "b": 1, "variants.k": 1, "webp": 1, "dt": 1 })
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
+        .ok_or_else(|| AppError::NotFound("Image not found".into()))?;
+    if by_token && !doc.get_str("dt").is_ok_and(|hash| auth::delete_token_matches(&req, hash)) {
+        return Err(AppError::Unauthorized("Invalid delete token".into()));
+    }
+
+    remove_image(&state, &id, &doc).await?;
+
+    log::info!("Deleted by {caller}: {id}");
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "id": id })))
+}
+
+// POST /i/delete
+pub async fn bulk_delete(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<BulkDeleteRequest>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_DELETE)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+    s3_available(&state)?;
+
+    let ids: Vec<String> = body
+        .ids
+        .iter()
+        .filter(|id| !id.is_empty())
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if ids.is_empty() {
+        return Err(AppError::BadRequest("No ids provided".into()));
+    }
+    if ids.len() > MAX_BULK_DELETE {
+        return Err(AppError::BadRequest(format!(
+            "Max {MAX_BULK_DELETE} ids per request"
+        )));
+    }
+
+    let docs = find_images(
+        &state,
+        mongodb::bson::doc! { "_id": { "$in": &ids } },
+        Some(mongodb::bson::doc! { "f": 1, "b": 1, "variants.k": 1, "webp": 1 }),
+        ("_id", 1),
+        i64::try_from(ids.len()).unwrap_or(i64::MAX),
+    )
+    .await?;
+
+    // id → every R2 key it owns, by bucket, for images that exist
+    let found: HashMap<String, BTreeMap<String, Vec<String>>> = docs
+        .iter()
+        .filter_map(|d| {
+            Some((d.get_str("_id").ok()?.to_string(), owned_objects(&state.config, d)?))
+        })
+        .collect();
+
+    let mut results: BTreeMap<String, &str> = ids
+        .iter()
+        .map(|id| {
+            let status = if found.contains_key(id) { "deleted" } else { "not_found" };
+            (id.clone(), status)
+        })
+        .collect();
+
+    if !found.is_empty() {
+        let mut by_bucket: BTreeMap<&str, Vec<&String>> = BTreeMap::new();
+        for (bucket, keys) in found.values().flatten() {
+            by_bucket.entry(bucket).or_default().extend(keys);
+        }
+        // Originals shared with an image outside this batch stay
+        let batch: Vec<String> = found.keys().cloned().collect();
+        for (bucket, keys) in &mut by_bucket {
+            let owned: Vec<String> = keys.iter().map(|k| (*k).clone()).collect();
+            let shared = shared_keys(&state, bucket, &owned, &batch).await?;
+            keys.retain(|k| !shared.contains(*k));
+        }
+        by_bucket.retain(|_, keys| !keys.is_empty());
+
+        // Quiet mode only reports the keys that failed
+        let mut failed_keys: HashSet<(&str, String)> = HashSet::new();
+        for (bucket, keys) in by_bucket {
+            let objects = keys
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(*key).build())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+
+            match state
+                .s3
+                .delete_objects()
+                .bucket(bucket)
+                .delete(delete)
+                .send()
+                .await
+            {
+                Ok(out) => {
+                    s3_ok(&state);
+                    failed_keys.extend(
+                        out.errors()
+                            .iter()
+                            .filter_map(|e| Some((bucket, e.key()?.to_string()))),
+                    );
+                }
+                Err(e) => {
+                    note_s3_failure(&state, &e);
+                    log::error!("Bulk delete S3 failure in {bucket}: {e}");
+                    failed_keys.extend(keys.into_iter().map(|key| (bucket, key.clone())));
+                }
+            }
+        }
+
+        // Keep the document for anything still in storage so it can be retried
+        let mut removable = Vec::with_capacity(found.len());
+        for (id, objects) in &found {
+            let failed = objects.iter().any(|(bucket, keys)| {
+                keys.iter().any(|k| failed_keys.contains(&(bucket.as_str(), k.clone())))
+            });
+            if failed {
+                results.insert(id.clone(), "error");
+            } else {
+                removable.push(id);
+            }
+        }
+
+        let mut by_shard: BTreeMap<usize, Vec<&String>> = BTreeMap::new();
+        for id in removable {
+            by_shard.entry(state.shard_of(id)).or_default().push(id);
+        }
+        for (shard, removable) in by_shard {
+            if let Err(e) = state.images[shard]
+                .delete_many(mongodb::bson::doc! { "_id": { "$in": &removable } })
+                .await
+            {
+                log::error!("Bulk delete MongoDB failure: {e}");
+                for id in &removable {
+                    results.insert((*id).clone(), "error");
+                }
+            }
+        }
+    }
+
+    cache_del(&state, &ids).await;
+
+    log::info!("Bulk delete by {key_id}: {} ids", ids.len());
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "results": results })))
+}
+
+// POST /i/{id}/rename
+pub async fn rename_image(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<RenameRequest>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_WRITE)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+    s3_available(&state)?;
+    let id = path.into_inner();
+    let name = sanitize_name(&body.name, &state.config)?;
+    let orig_name = (state.config.safe_keys || state.config.content_addressed)
+        .then(|| body.name.trim().to_string());
+    let name = if state.config.safe_keys {
+        safe_key_name(&name)
+    } else {
+        name
+    };
+
+    let doc = state
+        .images_for(&id)
+        .find_one(mongodb::bson::doc! { "_id": &id })
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
+        .ok_or_else(|| AppError::NotFound("Image not found".into()))?;
+    let old_key = doc.get_str("f").unwrap_or("").to_string();
+    // The object is renamed in place, in the cold bucket if it has moved there
+    let cold = doc.get_str("b").ok().map(String::from);
+    let bucket = cold.as_deref().unwrap_or(&state.config.r2_bucket);
+
+    // Keep the original folders (shard and date), only the file name changes. A content-addressed
+    // key never changes, so renaming one only updates the display and download name.
+    let folder = old_key.rsplit_once('/').map_or("", |(folder, _)| folder);
+    let new_key = if is_content_key(&old_key) {
+        old_key.clone()
+    } else if folder.is_empty() {
+        name
+    } else {
+        format!("{folder}/{name}")
+    };
+
+    let key_changed = new_key != old_key;
+    // With `SAFE_KEYS` two names can share a key, and then only the download name changes
+    let name_changed = orig_name.as_deref().is_some_and(|n| doc.get_str("orig_name").ok() != Some(n));
+
+    if key_changed || name_changed {
+        if key_changed && object_exists(&state, bucket, &new_key).await? {
+            return Err(AppError::Conflict(format!("{new_key} already exists")));
+        }
+
+        let mut copy = state
+            .s3
+            .copy_object()
+            .bucket(bucket)
+            .copy_source(format!("{bucket}/{}", encode_key(&old_key)))
+            .key(&new_key);
+        if let Some(orig_name) = &orig_name {
+            // Replacing the stored headers is the only way to change `Content-Disposition`,
+            // so the rest are written again alongside it
+            let head = state
+                .s3
+                .head_object()
+                .bucket(bucket)
+                .key(&old_key)
+                .send()
+                .await
+                .map_err(|e| s3_error(&state, &e))?;
+            copy = copy
+                .metadata_directive(MetadataDirective::Replace)
+                .set_content_type(head.content_type().map(String::from))
+                .set_cache_control(state.config.object_cache_control.clone())
+                .content_disposition(content_disposition(orig_name))
+                .set_metadata(object_metadata(&state.config));
+        }
+        copy.send().await.map_err(|e| s3_error(&state, &e))?;
+        s3_ok(&state);
+
+        let mut fields = mongodb::bson::doc! { "f": &new_key };
+        if let Some(orig_name) = &orig_name {
+            fields.insert("orig_name", orig_name);
+        }
+        // Matching the bucket too keeps a concurrent cold migration from being overwritten
+        let updated = state
+            .images_for(&id)
+            .update_one(
+                mongodb::bson::doc! { "_id": &id, "f": &old_key, "b": &cold },
+                mongodb::bson::doc! { "$set": fields },
+            )
+            .await
+            .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))
+            .and_then(|res| {
+                if res.matched_count == 0 {
+                    Err(AppError::Conflict("Image changed during the rename, try again".into()))
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(e) = updated {
+            // Roll back the copy so the document keeps pointing at a live object
+            if key_changed {
+                let _ = state
+                    .s3
+                    .delete_object()
+                    .bucket(bucket)
+                    .key(&new_key)
+                    .send()
+                    .await;
+            }
+            return Err(e);
+        }
+
+        // The copy is live and referenced; a failed cleanup only leaves an orphan behind
+        if key_changed {
+            if let Err(e) = state
+                .s3
+                .delete_object()
+                .bucket(bucket)
+                .key(&old_key)
+                .send()
+                .await
+            {
+                log::warn!("Rename left orphan {old_key}: {e}");
+            }
+        }
+
+        cache_del(&state, std::slice::from_ref(&id)).await;
+        log::info!("Renamed by {key_id}: {id} {old_key} → {new_key}");
+    }
+
+    let url = if cold.is_some() {
+        cold_url(&state.config, &new_key)
+    } else {
+        public_url(&state.config, &new_key)
+    };
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "id": id, "key": new_key, "url": url })))
+}
+
+// POST /i/{id}/rotate-id — revokes shared links by moving the image to a fresh id
+pub async fn rotate_image_id(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_WRITE)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+    let old_id = path.into_inner();
+
+    let mut doc = state
+        .images_for(&old_id)
+        .find_one(mongodb::bson::doc! { "_id": &old_id })
+        .await
+        .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?
+        .ok_or_else(|| AppError::NotFound("Image not found".into()))?;
+
+    // Insert before deleting so the image is never without a document; retry on id collisions
+    let mut new_id = None;
+    for _ in 0..ROTATE_ID_ATTEMPTS {
+        let candidate = gen_id();
+        doc.insert("_id", &candidate);
+        match state.images_for(&candidate).insert_one(&doc).await {
+            Ok(_) => {
+                new_id = Some(candidate);
+                break;
+            }
+            Err(e) if is_duplicate_key(&e) => continue,
+            Err(e) => return Err(AppError::Internal(format!("MongoDB: {e}"))),
+        }
+    }
+    let new_id = new_id.ok_or_else(|| AppError::Internal("Could not allocate a new id".into()))?;
+
+    if let Err(e) = state
+        .images_for(&old_id)
+        .delete_one(mongodb::bson::doc! { "_id": &old_id })
+        .await
+    {
+        // Roll back so the old link doesn't silently stay valid next to the new one
+        let _ = state
+            .images_for(&new_id)
+            .delete_one(mongodb::bson::doc! { "_id": &new_id })
+            .await;
+        return Err(AppError::Internal(format!("MongoDB: {e}")));
+    }
+
+    cache_del(&state, &[old_id.clone(), new_id.clone()]).await;
+    log::info!("Id rotated by {key_id}: {old_id} → {new_id}");
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "id": new_id, "old": old_id })))
+}
+
+/// Images lacking a field the current config would compute at completion; `None` when
+/// processing is off entirely
+fn backfill_filter(config: &Config) -> Option<mongodb::bson::Document> {
+    let mut missing = Vec::new();
+    if config.dominant_color {
+        missing.push(mongodb::bson::doc! { "color": { "$exists": false } });
+    }
+    if config.phash {
+        missing.push(mongodb::bson::doc! { "phash": { "$exists": false } });
+    }
+    if !config.variant_widths.is_empty() {
+        missing.push(mongodb::bson::doc! { "variants": { "$exists": false } });
+    }
+    if missing.is_empty() {
+        return None;
+    }
+    missing.push(mongodb::bson::doc! { "w": { "$exists": false } });
+    Some(mongodb::bson::doc! { "$or": missing })
+}
+
+/// Walks images missing derived fields in `_id` order, computing them from the stored object.
+/// Progress is checkpointed in `imgdock.jobs` after every batch so a restart resumes where it
+/// stopped; only missing fields are selected, so re-running is harmless.
+async fn run_backfill(state: web::Data<AppState>, filter: mongodb::bson::Document) {
+    let checkpoint = mongodb::bson::doc! { "_id": BACKFILL_JOB };
+    let mut after = match state.jobs.find_one(checkpoint.clone()).await {
+        Ok(doc) => doc.and_then(|d| d.get_str("after").ok().map(str::to_string)),
+        Err(e) => {
+            log::error!("Backfill checkpoint unreadable: {e}");
+            state.backfill_running.store(false, Ordering::Release);
+            return;
+        }
+    };
+    let mut updated = 0u64;
+    let mut scanned = 0u64;
+
+    log::info!("Backfill started after {}", after.as_deref().unwrap_or("the beginning"));
+
+    loop {
+        let mut batch_filter = filter.clone();
+        if let Some(after) = &after {
+            batch_filter.insert("_id", mongodb::bson::doc! { "$gt": after });
+        }
+        let batch = match find_images(
+            &state,
+            batch_filter,
+            Some(mongodb::bson::doc! { "f": 1, "b": 1 }),
+            ("_id", 1),
+            BACKFILL_BATCH,
+        )
+        .await
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                log::error!("Backfill stopped: {e}");
+                break;
+            }
+        };
+        let Some(last) = batch.last().and_then(|d| d.get_str("_id").ok()) else {
+            if let Err(e) = state.jobs.delete_one(checkpoint.clone()).await {
+                log::warn!("Backfill checkpoint not cleared: {e}");
+            }
+            log::info!("Backfill complete: {updated} updated, {scanned} scanned");
+            break;
+        };
+        let last = last.to_string();
+
+        for doc in &batch {
+            let (Ok(id), Ok(key)) = (doc.get_str("_id"), doc.get_str("f")) else {
+                continue;
+            };
+            scanned += 1;
+            // Vector and video uploads never get dimensions; the cursor moves past them
+            if !mime::from_filename(key).is_some_and(imaging::is_raster) {
+                continue;
+            }
+            // Backfill only adds derived fields; a slow decode never removes a stored image
+            let bucket = doc.get_str("b").unwrap_or(&state.config.r2_bucket);
+            let Ok(Some(processed)) = process_image(&state, id, bucket, key).await else {
+                continue;
+            };
+            match state
+                .images_for(id)
+                .update_one(
+                    mongodb::bson::doc! { "_id": id },
+                    mongodb::bson::doc! { "$set": processed.fields() },
+                )
+                .await
+            {
+                Ok(_) => {
+                    updated += 1;
+                    cache_del(&state, &[id.to_string()]).await;
+                }
+                Err(e) => log::warn!("Backfill update failed for {id}: {e}"),
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(state.config.backfill_delay_ms)).await;
+        }
+
+        let (_, now) = now_parts();
+        if let Err(e) = state
+            .jobs
+            .update_one(
+                checkpoint.clone(),
+                mongodb::bson::doc! { "$set": { "after": &last, "at": now } },
+            )
+            .upsert(true)
+            .await
+        {
+            log::warn!("Backfill checkpoint not saved: {e}");
+        }
+        log::info!("Backfill progress: {updated} updated, {scanned} scanned, up to {last}");
+        after = Some(last);
+    }
+
+    state.backfill_running.store(false, Ordering::Release);
+}
+
+// POST /admin/backfill — compute missing dimensions, colors, hashes and variants for stored images
+pub async fn start_backfill(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_ADMIN)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+
+    let filter = backfill_filter(&state.config).ok_or_else(|| {
+        AppError::BadRequest(
+            "Nothing to backfill: enable VARIANT_WIDTHS, DOMINANT_COLOR or PHASH".into(),
+        )
+    })?;
+    if state
+        .backfill_running
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(AppError::Conflict("Backfill already running".into()));
+    }
+
+    log::info!("Backfill requested by {key_id}");
+    actix_web::rt::spawn(run_backfill(state.clone(), filter));
+
+    Ok(HttpResponse::Accepted().json(json!({ "ok": OkFlag(true) })))
+}
+
+// POST /admin/cache/warm — rebuild the cached payloads of the given ids from MongoDB
+pub async fn warm_cache(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Json<CacheWarmRequest>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_ADMIN)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+    let Some(redis) = &state.redis else {
+        return Err(AppError::Unavailable("Redis is not configured".into()));
+    };
+
+    let ids: Vec<String> = body
+        .ids
+        .iter()
+        .filter(|id| !id.is_empty())
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if ids.is_empty() {
+        return Err(AppError::BadRequest("No ids provided".into()));
+    }
+    if ids.len() > MAX_CACHE_WARM {
+        return Err(AppError::BadRequest(format!("Max {MAX_CACHE_WARM} ids per request")));
+    }
+
+    let docs = find_images(
+        &state,
+        mongodb::bson::doc! { "_id": { "$in": &ids } },
+        None,
+        ("_id", 1),
+        i64::try_from(ids.len()).unwrap_or(i64::MAX),
+    )
+    .await?;
+
+    let mut results: BTreeMap<String, &str> =
+        ids.iter().map(|id| (id.clone(), "not_found")).collect();
+    for doc in &docs {
+        let Ok(id) = doc.get_str("_id") else { continue };
+        // Same shape `load_payload` caches, so readers can't tell a warmed entry apart
+        let payload = payload_from_doc(&state.config, doc);
+        let status = match serde_json::to_string(&payload) {
+            Ok(json) => {
+                let set: Result<(), RedisError> = redis
+                    .set(
+                        state.config.redis_key("i", id),
+                        json,
+                        Some(Expiration::EX(CACHE_TTL_SECS)),
+                        None,
+                        false,
+                    )
+                    .await;
+                match set {
+                    Ok(()) => "warmed",
+                    Err(e) => {
+                        log::warn!("Cache warm of {id} failed: {e}");
+                        "error"
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("Cache warm of {id} failed: {e}");
+                "error"
+            }
+        };
+        results.insert(id.to_string(), status);
+    }
+
+    // Warmed ids must not be shadowed by a negative-cache marker, and missing ones must not
+    // keep serving a payload cached before they were removed
+    let stale: Vec<String> = results
+        .iter()
+        .filter(|(_, status)| **status == "not_found")
+        .map(|(id, _)| state.config.redis_key("i", id))
+        .chain(
+            results
+                .iter()
+                .filter(|(_, status)| **status == "warmed")
+                .map(|(id, _)| state.config.redis_key("miss", id)),
+        )
+        .collect();
+    if !stale.is_empty() {
+        let _: Result<(), _> = redis.del(stale).await;
+    }
+
+    log::info!("Cache warm by {key_id}: {} ids", ids.len());
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "results": results })))
+}
+
+// GET /admin/pending
+pub async fn pending_transfers(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_ADMIN)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+
+    let (pending, truncated) = list_pending(&state).await?;
+    let items: Vec<_> = pending
+        .into_iter()
+        .map(|(id, p, ttl)| json!({ "id": id, "key": p.key, "size": p.size, "ttl": ttl }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": OkFlag(true), "items": items, "truncated": truncated })))
+}
+
+// GET /admin/usage?tenant= — image count and stored MB for one tenant
+pub async fn tenant_usage(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<UsageQuery>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_ADMIN)?;
+    let _slot = claim_key_slot(&state, &key_id)?;
+
+    let tenant = query.tenant.trim();
+    if tenant.is_empty() {
+        return Err(AppError::BadRequest("tenant is required".into()));
+    }
+
+    let cache_key = state.config.redis_key("usage", tenant);
+    if let Some(redis) = &state.redis {
+        if let Ok(Some(cached)) = redis.get::<Option<String>, _>(&cache_key).await {
+            return Ok(HttpResponse::Ok()
+                .content_type(header::ContentType::json())
+                .body(cached));
+        }
+    }
+
+    let pipeline = [
+        mongodb::bson::doc! { "$match": { "o": tenant } },
+        mongodb::bson::doc! { "$group": { "_id": null, "n": { "$sum": 1 }, "s": { "$sum": "$s" } } },
+    ];
+    let groups = futures_util::future::try_join_all(state.images.iter().map(|images| {
+        let aggregate = images.aggregate(pipeline.clone());
+        async move { aggregate.await?.try_collect::<Vec<_>>().await }
+    }))
+    .await
+    .map_err(|e| AppError::Internal(format!("MongoDB: {e}")))?;
+
+    let (mut count, mut mb) = (0i64, 0.0f64);
+    for group in groups.iter().flatten() {
+        count += match group.get("n") {
+            Some(mongodb::bson::Bson::Int32(n)) => i64::from(*n),
+            Some(mongodb::bson::Bson::Int64(n)) => *n,
+            _ => 0,
+        };
+        mb += group.get_f64("s").unwrap_or(0.0);
+    }
+
+    let json = json!({
+        "ok": OkFlag(true),
+        "tenant": tenant,
+        "count": count,
+        "s": (mb * 100.0).round() / 100.0,
+    })
+    .to_string();
+    if let Some(redis) = &state.redis {
+        let _: Result<(), _> = redis
+            .set(&cache_key, &json, Some(Expiration::EX(USAGE_CACHE_SECS)), None, false)
+            .await;
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(header::ContentType::json())
+        .body(json))
+}
+
+/// The PNG the self-test uploads: 1x1, or the smallest blank image `MIN_WIDTH`, `MIN_HEIGHT`
+/// and `ASPECT_RATIO` accept, so completion doesn't reject it
+fn self_test_probe(config: &Config) -> Result<Vec<u8>, String> {
+    let (w, h) = (config.min_width.unwrap_or(1).max(1), config.min_height.unwrap_or(1).max(1));
+    let (w, h) = match config.aspect_ratio {
+        Some((rw, rh)) => {
+            let scale = w.div_ceil(rw).max(h.div_ceil(rh));
+            (rw.saturating_mul(scale), rh.saturating_mul(scale))
+        }
+        None => (w, h),
+    };
+    if (w, h) == (1, 1) {
+        return Ok(TRANSPARENT_PNG.to_vec());
+    }
+    imaging::blank_png(w, h).map_err(|e| format!("probe image: {e}"))
+}
+
+/// `SELFTEST=true` / `--selftest`: pushes a small PNG through the real backends the way a client
+/// would (presign, PUT to the presigned URL, complete, read back, delete) and removes it again.
+/// The error names the step that failed.
+pub async fn self_test(state: &AppState) -> Result<(), String> {
+    let id = gen_id();
+    let key = object_key(&state.config, &format!("selftest-{id}.png"));
+    let probe = self_test_probe(&state.config)?;
+
+    let presign_config = PresigningConfig::builder()
+        .expires_in(Duration::from_secs(300))
+        .build()
+        .map_err(|e| format!("presign: {e}"))?;
+    let presigned = state
+        .s3
+        .put_object()
+        .bucket(&state.config.r2_bucket)
+        .key(&key)
+        .content_type("image/png")
+        .set_cache_control(state.config.object_cache_control.clone())
+        .set_metadata(object_metadata(&state.config))
+        .presigned(presign_config)
+        .await
+        .map_err(|e| format!("presign: {e}"))?;
+
+    let mut put = reqwest::Client::new()
+        .put(presigned.uri())
+        .timeout(Duration::from_secs(state.config.request_timeout_secs))
+        .body(probe.clone());
+    for (name, value) in presigned.headers() {
+        put = put.header(name, value);
+    }
+    put.send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| format!("PUT to presigned URL: {e}"))?;
+    log::info!("Self-test: uploaded {key}");
+
+    let pending = PendingTransfer {
+        key: key.clone(),
+        size: probe.len() as u64,
+        description: String::new(),
+        tags: HashMap::new(),
+        orig_name: None,
+        acl: Vec::new(),
+        owner: None,
+        expires_in_secs: None,
+        crc32c: None,
+    };
+    // Completion moves a content-addressed upload to the key its bytes hash to
+    let expected_key = if state.config.content_addressed {
+        content_key(None, &key, &hex::encode(Sha256::digest(&probe)))
+    } else {
+        key.clone()
+    };
+    let result = async {
+        save_pending(state, &id, &pending)
+            .await
+            .map_err(|e| format!("save transfer: {e}"))?;
+        complete_locked(state, &id)
+            .await
+            .map_err(|e| format!("complete: {e}"))?;
+        log::info!("Self-test: completed {id}");
+        let payload = load_payload(state, &id).await.map_err(|e| format!("get: {e}"))?;
+        if payload.f != expected_key {
+            return Err(format!("get: stored key is {}, expected {expected_key}", payload.f));
+        }
+        log::info!("Self-test: read back {id}");
+        Ok(())
+    }
+    .await;
+
+    // Cleaned up whichever step failed, so a failing check leaves nothing behind
+    let cleanup = remove_self_test(state, &id, &key).await;
+    result.and(cleanup)
+}
+
+/// Deletes everything the self-test may have created: the document, its objects (or just the
+/// uploaded one when completion never recorded it), the pending record and the cache entry
+async fn remove_self_test(state: &AppState, id: &str, key: &str) -> Result<(), String> {
+    let doc = state
+        .images_for(id)
+        .find_one_and_delete(mongodb::bson::doc! { "_id": id })
+        .await
+        .map_err(|e| format!("delete: MongoDB: {e}"))?;
+    let mut keys = doc
+        .as_ref()
+        .and_then(owned_keys)
+        .unwrap_or_else(|| vec![key.to_string()]);
+    // A real upload of the same PNG may share the content-addressed original
+    let shared = shared_keys(state, &state.config.r2_bucket, &keys, &[id.to_string()])
+        .await
+        .map_err(|e| format!("delete: {e}"))?;
+    keys.retain(|k| !shared.contains(k));
+    for key in &keys {
+        state
+            .s3
+            .delete_object()
+            .bucket(&state.config.r2_bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| format!("delete: S3 {key}: {e}"))?;
+    }
+    clear_pending(state, id).await;
+    cache_del(state, &[id.to_string()]).await;
+    log::info!("Self-test: deleted {id}");
+    Ok(())
+}
+
+// GET /events — completions the caller uploaded or is listed on
+pub async fn events(state: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let caller = auth::authorize(&req, &state.config, state.jwt.as_ref(), auth::SCOPE_IMAGE_READ)?;
+    let rx = state.events.subscribe();
+
+    let body = stream::unfold((rx, caller), |(mut rx, caller)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) if !event.visible_to(&caller) => continue,
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    let frame = format!("event: transfer.completed\ndata: {data}\n\n");
+                    let frame = Ok::<_, actix_web::Error>(web::Bytes::from(frame));
+                    return Some((frame, (rx, caller)));
+                }
+                // A slow subscriber just misses the overflowed events
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body))
+}
+
+// GET /health — readiness: every backing store must answer
+pub async fn health(state: web::Data<AppState>) -> HttpResponse {
+    let admin = state.images[0].client().database("admin");
+    // Without a cache there is no Redis to check, so it is left out of the report
+    let redis_ping = async {
+        match &state.redis {
+            Some(redis) => Some(redis.ping::<String>().await.is_ok()),
+            None => None,
+        }
+    };
+    let (mongo, redis) = futures_util::join!(
+        admin.run_command(mongodb::bson::doc! { "ping": 1 }).into_future(),
+        redis_ping,
+    );
+    let mongo = mongo.is_ok();
+    let ready = mongo && redis.unwrap_or(true);
+
+    let mut body = json!({ "ok": OkFlag(ready), "mongo": u8::from(mongo) });
+    if let Some(redis) = redis {
+        body["redis"] = json!(u8::from(redis));
+    }
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// GET / — service identity, for reachability checks and confirming which build is deployed
+pub async fn index() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "ok": OkFlag(true),
+        "service": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+// GET /healthz — liveness: the process is up and serving
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "ok": OkFlag(true) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn format_allowed_matches_subtype_wildcards() {
+        let allowed = patterns(&["image/*"]);
+        assert!(format_allowed(&allowed, "image/png"));
+        assert!(format_allowed(&allowed, "image/svg+xml"));
+        assert!(!format_allowed(&allowed, "application/pdf"));
+        assert!(!format_allowed(&allowed, "image/"));
+        assert!(!format_allowed(&allowed, "image"));
+    }
+
+    #[test]
+    fn format_allowed_matches_exact_entries_and_star() {
+        let allowed = patterns(&["image/jpeg", "image/png"]);
+        assert!(format_allowed(&allowed, "image/png"));
+        assert!(!format_allowed(&allowed, "image/gif"));
+        assert!(format_allowed(&patterns(&["*"]), "application/pdf"));
+        assert!(!format_allowed(&[], "image/png"));
+    }
+
+    #[test]
+    fn blocked_formats_win_over_allowed_wildcards() {
+        let mut config = Config::for_tests();
+        config.allowed_formats = patterns(&["image/*"]);
+        config.blocked_formats = patterns(&["image/svg+xml"]);
+        assert!(check_format(&config, "image/png").is_ok());
+        assert!(matches!(
+            check_format(&config, "image/svg+xml"),
+            Err(AppError::BadRequest(msg)) if msg.contains("blocked")
+        ));
+        assert!(matches!(
+            check_format(&config, "application/pdf"),
+            Err(AppError::BadRequest(msg)) if msg.starts_with("Unsupported")
+        ));
+    }
+
+    #[test]
+    fn blocked_wildcards_cover_allowed_exact_entries() {
+        let mut config = Config::for_tests();
+        config.allowed_formats = patterns(&["*"]);
+        config.blocked_formats = patterns(&["image/*", "application/pdf"]);
+        assert!(check_format(&config, "video/mp4").is_ok());
+        assert!(check_format(&config, "image/png").is_err());
+        assert!(check_format(&config, "application/pdf").is_err());
+    }
+
+    #[test]
+    fn sanitize_name_maps_nfc_and_nfd_to_one_key() {
+        let config = Config::for_tests();
+        let nfc = sanitize_name("caf\u{e9}.jpg", &config).unwrap();
+        let nfd = sanitize_name("cafe\u{301}.jpg", &config).unwrap();
+        assert_eq!(nfc, nfd);
+        assert_eq!(object_key(&config, &nfc), object_key(&config, &nfd));
+    }
+
+    #[test]
+    fn lowercase_keys_folds_mixed_case_names() {
+        let mut config = Config::for_tests();
+        config.lowercase_keys = true;
+        let name = sanitize_name(" Photos/IMG_0042.JPG ", &config).unwrap();
+        assert_eq!(name, "photos/img_0042.jpg");
+        assert!(object_key(&config, &name).ends_with("/photos/img_0042.jpg"));
+        assert_eq!(
+            sanitize_name("img_0042.jpg", &config).unwrap(),
+            sanitize_name("IMG_0042.jpg", &config).unwrap()
+        );
+
+        config.lowercase_keys = false;
+        assert_eq!(sanitize_name("Photos/IMG_0042.JPG", &config).unwrap(), "Photos/IMG_0042.JPG");
+    }
+
+    #[test]
+    fn encode_key_escapes_segments_but_keeps_separators() {
+        assert_eq!(encode_key("2024/01/02/my photo.jpg"), "2024/01/02/my%20photo.jpg");
+        assert_eq!(encode_key("2024/01/02/a+b.png"), "2024/01/02/a%2Bb.png");
+        assert_eq!(encode_key("2024/01/02/写真.jpg"), "2024/01/02/%E5%86%99%E7%9C%9F.jpg");
+        assert_eq!(encode_key("2024/01/02/cafe\u{301}.jpg"), "2024/01/02/cafe%CC%81.jpg");
+        let mut config = Config::for_tests();
+        config.r2_cdn_domain = None;
+        assert_eq!(
+            public_url(&config, "2024/01/02/a b+c.jpg"),
+            "https://cdn.example.com/2024/01/02/a%20b%2Bc.jpg"
+        );
+    }
+
+    #[test]
+    fn check_upload_reports_every_problem() {
+        let mut config = Config::for_tests();
+        config.infer_content_type = true;
+        config.allowed_formats = patterns(&["image/png"]);
+        config.blocked_filenames = vec![".htaccess".into()];
+
+        let mut errors = Vec::new();
+        let (name, content_type) = check_upload(&config, " shot.png ", "", &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!((name.as_deref(), content_type.as_str()), (Some("shot.png"), "image/png"));
+
+        let (name, _) = check_upload(&config, ".htaccess", "Image/GIF", &mut errors);
+        assert!(name.is_none());
+        assert_eq!(errors.len(), 2, "reserved name and format are both reported");
+    }
+
+    fn pending(size: u64) -> PendingTransfer {
+        serde_json::from_value(json!({ "key": "2024/01/02/a.png", "size": size })).unwrap()
+    }
+
+    #[test]
+    fn size_check_rejects_oversize_and_strict_mismatches() {
+        let mut config = Config::for_tests();
+        config.max_size = 1000;
+        config.strict_size = false;
+
+        let mut upload = pending(500);
+        assert!(reconcile_size(&config, "id", &mut upload, 500).is_ok());
+        // A lenient mismatch stores the real size and keeps going
+        assert!(reconcile_size(&config, "id", &mut upload, 600).is_ok());
+        assert_eq!(upload.size, 600);
+
+        let oversize = reconcile_size(&config, "id", &mut pending(500), 1001).unwrap_err();
+        assert!(matches!(oversize, AppError::LargePayload(_)));
+        assert!(rejects_upload(&oversize));
+
+        config.strict_size = true;
+        let mismatch = reconcile_size(&config, "id", &mut pending(500), 600).unwrap_err();
+        assert!(matches!(mismatch, AppError::Unprocessable(_)));
+        assert!(rejects_upload(&mismatch));
+    }
+
+    #[test]
+    fn checksum_mismatch_rejects_the_upload() {
+        assert!(verify_crc32c("id", "a.png", "AAAAAA==", Some("AAAAAA==")).is_ok());
+        assert!(verify_crc32c("id", "a.png", "AAAAAA==", None).is_ok());
+        let mismatch = verify_crc32c("id", "a.png", "AAAAAA==", Some("BBBBBB==")).unwrap_err();
+        assert!(rejects_upload(&mismatch));
+    }
+
+    #[test]
+    fn completion_failures_keep_the_pending_record_for_retry() {
+        // What each step fails with when the upload itself is fine; none of these may discard it
+        let retryable = [
+            // `head_uploaded`: not visible yet, or the store is down
+            AppError::BadRequest("File not uploaded to storage".into()),
+            storage_outage(30),
+            AppError::Internal("S3: dispatch failure".into()),
+            // `scan_upload` without `CLAMAV_FAIL_OPEN`
+            AppError::Unavailable("Virus scan unavailable, retry later".into()),
+            // Content addressing, or the insert (which also records an orphan)
+            AppError::Internal("MongoDB: timed out".into()),
+        ];
+        for e in &retryable {
+            assert!(!rejects_upload(e), "{e} should keep the pending record");
+        }
+    }
+
+    #[test]
+    fn completion_rejections_discard_the_upload() {
+        // Size, checksum, extension, malware, dimensions, SVG and decode-time rejections
+        let rejected = [
+            AppError::LargePayload("Max 1MB, uploaded file is 2000000 bytes".into()),
+            AppError::Unprocessable("Upload rejected: declared 1 bytes but uploaded 2".into()),
+            AppError::Unprocessable("Upload rejected: malware detected".into()),
+            AppError::Unprocessable("Upload rejected: image is 1x1, minimum width is 10".into()),
+            AppError::Unprocessable("Upload rejected: SVG could not be parsed".into()),
+            AppError::Unprocessable("Upload rejected: image took too long to decode".into()),
+        ];
+        for e in &rejected {
+            assert!(rejects_upload(e), "{e} should discard the upload");
+        }
+    }
+
+    #[actix_web::test]
+    async fn image_responses_carry_cors_and_corp_headers() {
+        use actix_web::{test, App};
+
+        let mut config = Config::for_tests();
+        config.resource_policy = Some("cross-origin".into());
+        config.not_found_image_url = None;
+        config.not_found_placeholder = true;
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::cors())
+                .app_data(web::Data::new(config))
+                .route(
+                    "/i/missing",
+                    web::get().to(|config: web::Data<Config>| async move {
+                        not_found_image(&config).unwrap()
+                    }),
+                )
+                .route(
+                    "/i/found",
+                    web::get().to(|config: web::Data<Config>| async move {
+                        let redirect = HttpResponse::Found()
+                            .insert_header((header::LOCATION, "https://cdn.example.com/a.png"))
+                            .finish();
+                        image_response(&config, redirect)
+                    }),
+                ),
+        )
+        .await;
+
+        for (path, status) in [("/i/found", 302), ("/i/missing", 404)] {
+            let req = test::TestRequest::get()
+                .uri(path)
+                .insert_header((header::ORIGIN, "https://app.example.com"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status().as_u16(), status);
+            let headers = res.headers();
+            assert_eq!(headers.get("cross-origin-resource-policy").unwrap(), "cross-origin");
+            assert!(headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN), "{path} lacks CORS");
+        }
+    }
 }
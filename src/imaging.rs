@@ -0,0 +1,240 @@
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+/// Longest side of the thumbnail sampled for the dominant color
+const COLOR_SAMPLE_SIZE: u32 = 32;
+
+/// A re-encoded WebP rendition of the source at a fixed width
+pub struct EncodedVariant {
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Whether the decoder can handle this MIME type (vector and video formats are skipped)
+pub fn is_raster(content_type: &str) -> bool {
+    matches!(
+        content_type,
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp"
+    )
+}
+
+/// MIME type implied by the file's magic bytes, for checking a declared type
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    image::guess_format(bytes).ok().map(|f| f.to_mime_type())
+}
+
+/// Average color of the image as `#rrggbb`, weighted by alpha so transparent areas don't pull
+/// it towards black. Computed on a small downscale, which is plenty for a placeholder color.
+pub fn dominant_color(img: &DynamicImage) -> Option<String> {
+    let small = img.resize(COLOR_SAMPLE_SIZE, COLOR_SAMPLE_SIZE, FilterType::Triangle).into_rgba8();
+
+    let (mut r, mut g, mut b, mut weight) = (0u64, 0u64, 0u64, 0u64);
+    for px in small.pixels() {
+        let [pr, pg, pb, pa] = px.0;
+        let a = u64::from(pa);
+        r += u64::from(pr) * a;
+        g += u64::from(pg) * a;
+        b += u64::from(pb) * a;
+        weight += a;
+    }
+    if weight == 0 {
+        return None;
+    }
+
+    Some(format!(
+        "#{:02x}{:02x}{:02x}",
+        r / weight,
+        g / weight,
+        b / weight
+    ))
+}
+
+/// 64-bit difference hash (dHash) as 16 hex chars: each bit says whether a pixel of a 9x8
+/// grayscale thumbnail is brighter than its right neighbour. Near-duplicates (re-encodes,
+/// resizes, light edits) land within a small Hamming distance of each other.
+pub fn dhash(img: &DynamicImage) -> String {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    format!("{hash:016x}")
+}
+
+/// Number of differing bits between two `dhash` values, `None` if either is malformed
+pub fn hash_distance(a: &str, b: &str) -> Option<u32> {
+    let a = u64::from_str_radix(a, 16).ok()?;
+    let b = u64::from_str_radix(b, 16).ok()?;
+    Some((a ^ b).count_ones())
+}
+
+/// Decodes and applies the EXIF orientation, so everything derived from the result (dimensions,
+/// variants) is upright even though the stored original keeps its raw pixel layout.
+/// The header is checked first: images whose decoded buffer would exceed `max_bytes` are
+/// refused before any pixel allocation, and the decoder itself is capped at the same budget.
+pub fn decode(bytes: &[u8], max_bytes: u64) -> Result<DynamicImage, String> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(max_bytes);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+    let needed = decoder.total_bytes();
+    if needed > max_bytes {
+        let (w, h) = decoder.dimensions();
+        return Err(format!(
+            "{w}x{h} needs {}MB decoded, over the {}MB budget",
+            needed / (1024 * 1024),
+            max_bytes / (1024 * 1024)
+        ));
+    }
+
+    // An unreadable orientation tag is treated as upright rather than failing the decode
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Upright `(width, height)` read from the header alone, with the EXIF orientation applied as
+/// in `decode`; no pixels are decoded
+pub fn dimensions(bytes: &[u8]) -> Result<(u32, u32), String> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .into_decoder()
+        .map_err(|e| e.to_string())?;
+    let (w, h) = decoder.dimensions();
+    let turned = matches!(
+        decoder.orientation().unwrap_or(Orientation::NoTransforms),
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH
+    );
+    Ok(if turned { (h, w) } else { (w, h) })
+}
+
+/// A fully transparent PNG of the given size
+pub fn blank_png(width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::new_rgba8(width, height)
+        .write_to(&mut out, ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// Encodes one WebP per requested width at lossy `quality` (1-100), preserving aspect ratio.
+/// Widths larger than the source are skipped rather than upscaled.
+pub fn webp_variants(img: &DynamicImage, widths: &[u32], quality: u8) -> Vec<EncodedVariant> {
+    let (src_w, src_h) = (img.width(), img.height());
+
+    widths
+        .iter()
+        .filter(|&&w| w > 0 && w <= src_w)
+        .map(|&width| {
+            let height = (u64::from(src_h) * u64::from(width) / u64::from(src_w)).max(1);
+            let height = u32::try_from(height).unwrap_or(u32::MAX);
+            let resized = img.resize_exact(width, height, FilterType::Lanczos3);
+            EncodedVariant {
+                width,
+                height,
+                bytes: encode_webp(&resized, quality),
+            }
+        })
+        .collect()
+}
+
+/// Encodes the image as-is to lossy WebP at `quality` (1-100)
+pub fn encode_webp(img: &DynamicImage, quality: u8) -> Vec<u8> {
+    let rgba = img.to_rgba8();
+    webp::Encoder::from_rgba(&rgba, img.width(), img.height())
+        .encode(f32::from(quality))
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// A 64x32 JPEG, red on the left half and blue on the right, tagged with EXIF orientation 6
+    /// (rotate 90° clockwise to display), as phone cameras write portrait shots
+    fn rotated_jpeg() -> Vec<u8> {
+        let img = RgbImage::from_fn(64, 32, |x, _| {
+            if x < 32 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let mut jpeg = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img).write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+        let jpeg = jpeg.into_inner();
+
+        // APP1 with a big-endian TIFF holding one IFD entry: Orientation (0x0112), SHORT, 6
+        let mut app1 = vec![0xFF, 0xE1, 0x00, 0x22];
+        app1.extend_from_slice(b"Exif\0\0MM\0\x2a\0\0\0\x08");
+        app1.extend_from_slice(&[0x00, 0x01, 0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+        app1.extend_from_slice(&[0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        [&jpeg[..2], &app1, &jpeg[2..]].concat()
+    }
+
+    fn is_red(px: [u8; 4]) -> bool {
+        px[0] > 200 && px[2] < 60
+    }
+
+    fn is_blue(px: [u8; 4]) -> bool {
+        px[2] > 200 && px[0] < 60
+    }
+
+    #[test]
+    fn rotated_sample_is_upright_in_dimensions_and_decode() {
+        let bytes = rotated_jpeg();
+        assert_eq!(dimensions(&bytes).unwrap(), (32, 64));
+
+        let img = decode(&bytes, 64 * 1024 * 1024).unwrap().into_rgba8();
+        assert_eq!(img.dimensions(), (32, 64));
+        // Rotating clockwise puts the left (red) half on top
+        assert!(is_red(img.get_pixel(16, 8).0));
+        assert!(is_blue(img.get_pixel(16, 56).0));
+    }
+
+    #[test]
+    fn header_after_large_segments_needs_more_than_a_prefix() {
+        let jpeg = rotated_jpeg();
+        // Five full APP2 segments (as an embedded ICC profile would be) ahead of the frame header
+        let mut app2 = Vec::new();
+        for _ in 0..5 {
+            app2.extend_from_slice(&[0xFF, 0xE2, 0xFF, 0xFF]);
+            app2.extend(std::iter::repeat_n(0, 0xFFFF - 2));
+        }
+        let bytes = [&jpeg[..2], &app2, &jpeg[2..]].concat();
+        assert!(dimensions(&bytes[..256 * 1024]).is_err());
+        assert_eq!(dimensions(&bytes).unwrap(), (32, 64));
+    }
+
+    #[test]
+    fn thumbnails_of_rotated_sample_keep_its_orientation() {
+        let img = decode(&rotated_jpeg(), 64 * 1024 * 1024).unwrap();
+        let variants = webp_variants(&img, &[16, 128], 90);
+        assert_eq!(variants.len(), 1, "widths over the source are skipped");
+        let thumb = &variants[0];
+        assert_eq!((thumb.width, thumb.height), (16, 32));
+
+        let decoded = image::load_from_memory(&thumb.bytes).unwrap().into_rgba8();
+        assert_eq!(decoded.dimensions(), (16, 32));
+        assert!(is_red(decoded.get_pixel(8, 4).0));
+        assert!(is_blue(decoded.get_pixel(8, 28).0));
+    }
+}
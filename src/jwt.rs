@@ -0,0 +1,185 @@
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// Upper bound on fetching the JWKS document
+const JWKS_TIMEOUT_SECS: u64 = 10;
+
+/// The claims this service reads; signature, `exp`, `iss` and `aud` are checked by the decoder
+#[derive(Deserialize)]
+pub struct Claims {
+    pub sub: Option<String>,
+    /// OAuth-style space-separated scopes
+    #[serde(default)]
+    scope: String,
+    /// Scopes as an array, as some issuers emit them
+    #[serde(default)]
+    scp: Vec<String>,
+}
+
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope) || self.scp.iter().any(|s| s == scope)
+    }
+}
+
+/// A verification key and the algorithms it may be used with
+struct Key {
+    kid: Option<String>,
+    key: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+const RSA_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+const EC_ALGORITHMS: &[Algorithm] = &[Algorithm::ES256, Algorithm::ES384];
+const ED_ALGORITHMS: &[Algorithm] = &[Algorithm::EdDSA];
+
+/// Algorithms a key type can verify; the token header only picks among these
+fn family(params: &AlgorithmParameters) -> Option<&'static [Algorithm]> {
+    match params {
+        AlgorithmParameters::RSA(_) => Some(RSA_ALGORITHMS),
+        AlgorithmParameters::EllipticCurve(_) => Some(EC_ALGORITHMS),
+        AlgorithmParameters::OctetKeyPair(_) => Some(ED_ALGORITHMS),
+        // Shared secrets don't belong in a published key set
+        AlgorithmParameters::OctetKey(_) => None,
+    }
+}
+
+/// Parses a PEM public key, trying RSA, then EC, then Ed25519
+fn pem_key(pem: &str) -> Result<Key, String> {
+    let pem = pem.as_bytes();
+    let (key, algorithms) = if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+        (key, RSA_ALGORITHMS)
+    } else if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+        (key, EC_ALGORITHMS)
+    } else if let Ok(key) = DecodingKey::from_ed_pem(pem) {
+        (key, ED_ALGORITHMS)
+    } else {
+        return Err("not an RSA, EC or Ed25519 public key".into());
+    };
+    Ok(Key {
+        kid: None,
+        key,
+        algorithms: algorithms.to_vec(),
+    })
+}
+
+/// Fetches and parses a JWKS document, skipping keys this service can't use
+async fn fetch_jwks(url: &str) -> Result<Vec<Key>, String> {
+    let set: JwkSet = reqwest::Client::new()
+        .get(url)
+        .timeout(Duration::from_secs(JWKS_TIMEOUT_SECS))
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let keys: Vec<Key> = set
+        .keys
+        .iter()
+        .filter_map(|jwk| {
+            let mut algorithms = family(&jwk.algorithm)?.to_vec();
+            // A key pinned to one algorithm may only be used with that one
+            if let Some(pinned) = jwk.common.key_algorithm {
+                let pinned = Algorithm::from_str(&pinned.to_string()).ok()?;
+                algorithms.retain(|a| *a == pinned);
+            }
+            let key = DecodingKey::from_jwk(jwk).ok()?;
+            Some(Key {
+                kid: jwk.common.key_id.clone(),
+                key,
+                algorithms,
+            })
+        })
+        .filter(|k| !k.algorithms.is_empty())
+        .collect();
+
+    if keys.is_empty() {
+        return Err("no usable RSA, EC or Ed25519 keys".into());
+    }
+    Ok(keys)
+}
+
+/// Verifies bearer tokens against `JWT_PUBLIC_KEY` or the keys published at `JWT_JWKS_URL`
+pub struct Verifier {
+    keys: RwLock<Vec<Key>>,
+    jwks_url: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl Verifier {
+    /// `None` when JWT auth isn't configured. A malformed `JWT_PUBLIC_KEY` or an unreachable
+    /// `JWT_JWKS_URL` fails startup rather than silently rejecting every token.
+    pub async fn from_config(config: &Config) -> Option<Self> {
+        let keys = if let Some(pem) = &config.jwt_public_key {
+            vec![pem_key(pem).unwrap_or_else(|e| panic!("❌ Invalid JWT_PUBLIC_KEY: {e}"))]
+        } else if let Some(url) = &config.jwt_jwks_url {
+            fetch_jwks(url)
+                .await
+                .unwrap_or_else(|e| panic!("❌ Could not load JWT_JWKS_URL: {e}"))
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            keys: RwLock::new(keys),
+            jwks_url: config
+                .jwt_jwks_url
+                .clone()
+                .filter(|_| config.jwt_public_key.is_none()),
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+        })
+    }
+
+    /// Re-reads the JWKS so rotated keys are picked up; keeps the old set on failure
+    pub async fn refresh(&self) {
+        let Some(url) = &self.jwks_url else {
+            return;
+        };
+        match fetch_jwks(url).await {
+            Ok(keys) => *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys,
+            Err(e) => log::warn!("⚠ JWKS refresh failed, keeping previous keys: {e}"),
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let key = keys
+            .iter()
+            .filter(|k| header.kid.is_none() || k.kid.is_none() || k.kid == header.kid)
+            .find(|k| k.algorithms.contains(&header.alg))
+            .ok_or("no key for this token")?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms.clone_from(&key.algorithms);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        jsonwebtoken::decode::<Claims>(token, &key.key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+}
@@ -1,15 +1,49 @@
+mod auth;
+mod av;
 mod config;
 mod handlers;
+mod imaging;
+mod jwt;
+mod metadata;
+mod mime;
 mod models;
+mod net;
+mod remote;
+mod svg;
 
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::dev::Service;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{KeepAlive, Method, StatusCode};
+use actix_web::middleware::{Compress, Logger};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use aws_config::timeout::TimeoutConfig;
 use aws_sdk_s3::Client as S3Client;
 use fred::prelude::*;
-use mongodb::Client as MongoClient;
+use futures_util::future::{ready, Either};
+use mongodb::options::{ClientOptions, IndexOptions};
+use mongodb::{Client as MongoClient, IndexModel};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, Semaphore};
 
 use config::Config;
 use handlers::AppState;
+use models::AppError;
+
+/// Marks responses the compression layer should leave alone
+const IDENTITY: &str = "identity";
+
+/// How often keys are re-read from `JWT_JWKS_URL`
+const JWKS_REFRESH_SECS: u64 = 3600;
+
+/// How often images past their `exp` are deleted
+const EXPIRY_SWEEP_SECS: u64 = 3600;
+
+/// How often originals older than `COLD_AFTER_DAYS` are moved to `R2_COLD_BUCKET`
+const COLD_MIGRATION_SECS: u64 = 3600;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -18,11 +52,36 @@ async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
     let config = Config::from_env();
+    models::set_bool_ok(config.bool_ok);
     let port = config.port;
+    let keep_alive = config.http_keep_alive_secs.map(|secs| match secs {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    });
+    let http2 = config.http2;
+    let request_timeout = Duration::from_secs(config.request_timeout_secs);
+    let compress_min_bytes = config.compress_min_bytes;
+
+    // Dimensions are only read while processing images; without them every url stays plain
+    if config.url_template.is_some()
+        && config.variant_widths.is_empty()
+        && !config.dominant_color
+        && !config.phash
+    {
+        log::warn!("⚠ URL_TEMPLATE has no effect unless VARIANT_WIDTHS, DOMINANT_COLOR or PHASH is set");
+    }
 
     log::info!("🔌 Connecting to services...");
 
     // S3 Client (R2)
+    let mut s3_timeouts = TimeoutConfig::builder();
+    if let Some(ms) = config.s3_connect_timeout_ms {
+        s3_timeouts = s3_timeouts.connect_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = config.s3_operation_timeout_ms {
+        s3_timeouts = s3_timeouts.operation_timeout(Duration::from_millis(ms));
+    }
+
     let s3_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
         .endpoint_url(&config.r2_endpoint)
         .credentials_provider(aws_credential_types::Credentials::new(
@@ -32,74 +91,450 @@ async fn main() -> std::io::Result<()> {
             None,
             "r2",
         ))
-        .region(aws_config::Region::new("auto"))
+        .region(aws_config::Region::new(config.s3_region.clone()))
+        .timeout_config(s3_timeouts.build())
         .load()
         .await;
 
     let s3 = S3Client::new(&s3_config);
 
     // MongoDB
-    let mongo = MongoClient::with_uri_str(&config.mongo_uri)
+    let mut mongo_options = ClientOptions::parse(&config.mongo_uri)
         .await
-        .expect("❌ MongoDB connection failed");
+        .expect("❌ Invalid MONGO_URI");
+    if let Some(size) = config.mongo_max_pool_size {
+        mongo_options.max_pool_size = Some(size);
+    }
+    if let Some(ms) = config.mongo_connect_timeout_ms {
+        mongo_options.connect_timeout = Some(Duration::from_millis(ms));
+    }
+    if let Some(ms) = config.mongo_server_selection_timeout_ms {
+        mongo_options.server_selection_timeout = Some(Duration::from_millis(ms));
+    }
+    let mongo = MongoClient::with_options(mongo_options).expect("❌ MongoDB connection failed");
 
-    let collection = mongo
-        .database("imgdock")
-        .collection::<mongodb::bson::Document>("i");
+    let images: Vec<_> = config
+        .image_collections()
+        .iter()
+        .map(|name| mongo.database("imgdock").collection::<mongodb::bson::Document>(name))
+        .collect();
 
     mongo
         .database("admin")
         .run_command(mongodb::bson::doc! { "ping": 1 })
         .await
-        .expect("❌ MongoDB ping failed");
+        .unwrap_or_else(|e| panic!("❌ MongoDB ping failed: {e}"));
     log::info!("✓ MongoDB connected");
 
+    // Every image collection gets the same indexes
+    for collection in &images {
+        // Wildcard index so `?tag=key:value` listing filters stay indexed for any tag key
+        if let Err(e) = collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(mongodb::bson::doc! { "tags.$**": 1 })
+                    .build(),
+            )
+            .await
+        {
+            log::warn!("⚠ Could not create tags index: {e}");
+        }
+
+        // Stale-image scans filter on last access
+        if config.track_last_access {
+            if let Err(e) = collection
+                .create_index(IndexModel::builder().keys(mongodb::bson::doc! { "la": 1 }).build())
+                .await
+            {
+                log::warn!("⚠ Could not create last-access index: {e}");
+            }
+        }
+
+        // Name and content dedup look images up by key on every transfer
+        if config.name_dedup || config.content_addressed {
+            if let Err(e) = collection
+                .create_index(IndexModel::builder().keys(mongodb::bson::doc! { "f": 1 }).build())
+                .await
+            {
+                log::warn!("⚠ Could not create key index: {e}");
+            }
+        }
+
+        // Mirrors are deduplicated by source URL
+        if let Err(e) = collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(mongodb::bson::doc! { "src": 1 })
+                    .options(IndexOptions::builder().sparse(true).build())
+                    .build(),
+            )
+            .await
+        {
+            log::warn!("⚠ Could not create source index: {e}");
+        }
+
+        // Per-tenant usage totals match on the uploader
+        if let Err(e) = collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(mongodb::bson::doc! { "o": 1 })
+                    .options(IndexOptions::builder().sparse(true).build())
+                    .build(),
+            )
+            .await
+        {
+            log::warn!("⚠ Could not create tenant index: {e}");
+        }
+
+        // The expiry sweep looks for images whose `exp` has passed
+        if let Err(e) = collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(mongodb::bson::doc! { "exp": 1 })
+                    .options(IndexOptions::builder().sparse(true).build())
+                    .build(),
+            )
+            .await
+        {
+            log::warn!("⚠ Could not create expiry index: {e}");
+        }
+
+        // The cold migration looks for old originals still in the hot bucket
+        if config.r2_cold_bucket.is_some() {
+            if let Err(e) = collection
+                .create_index(
+                    IndexModel::builder()
+                        .keys(mongodb::bson::doc! { "b": 1, "t": 1 })
+                        .build(),
+                )
+                .await
+            {
+                log::warn!("⚠ Could not create storage tier index: {e}");
+            }
+        }
+    }
+
+    // Capped, so the audit trail trims itself; an existing collection keeps its original size
+    let access_log = mongo
+        .database("imgdock")
+        .collection::<mongodb::bson::Document>("access_log");
+    if config.access_log {
+        if let Err(e) = mongo
+            .database("imgdock")
+            .create_collection("access_log")
+            .capped(true)
+            .size(config.access_log_size_mb * 1024 * 1024)
+            .await
+        {
+            let exists = matches!(&*e.kind, mongodb::error::ErrorKind::Command(c) if c.code == 48);
+            if !exists {
+                log::warn!("⚠ Could not create access log collection: {e}");
+            }
+        }
+    }
+
+    // Pending transfers fall back to a TTL-indexed collection when running without Redis
+    let pending = mongo
+        .database("imgdock")
+        .collection::<mongodb::bson::Document>("pending");
+
+    // Resume points for admin-triggered background jobs
+    let jobs = mongo
+        .database("imgdock")
+        .collection::<mongodb::bson::Document>("jobs");
+
+    // Objects stored without an image document, for manual or scripted cleanup
+    let orphans = mongo
+        .database("imgdock")
+        .collection::<mongodb::bson::Document>("orphans");
+
     // Redis
-    let redis_config =
-        RedisConfig::from_url(&config.redis_url).expect("❌ Invalid Redis URL");
+    let redis_client = match &config.redis_url {
+        Some(url) => {
+            let redis_config = RedisConfig::from_url(url).expect("❌ Invalid Redis URL");
 
-    let redis_client = RedisClient::new(redis_config, None, None, None);
-    redis_client.connect();
-    redis_client
-        .wait_for_connect()
-        .await
-        .expect("❌ Redis connection failed");
+            let redis_client = RedisClient::new(redis_config, None, None, None);
+            redis_client.connect();
+            redis_client
+                .wait_for_connect()
+                .await
+                .expect("❌ Redis connection failed");
 
-    redis_client
-        .ping::<String>()
-        .await
-        .expect("❌ Redis ping failed");
-    log::info!("✓ Redis connected");
+            redis_client
+                .ping::<String>()
+                .await
+                .expect("❌ Redis ping failed");
+            log::info!("✓ Redis connected");
+            Some(redis_client)
+        }
+        None => {
+            if let Err(e) = pending
+                .create_index(
+                    IndexModel::builder()
+                        .keys(mongodb::bson::doc! { "exp": 1 })
+                        .options(
+                            IndexOptions::builder()
+                                .expire_after(Duration::from_secs(0))
+                                .build(),
+                        )
+                        .build(),
+                )
+                .await
+            {
+                log::warn!("⚠ Could not create pending TTL index: {e}");
+            }
+            log::info!("✓ Cache disabled, running without Redis");
+            None
+        }
+    };
+
+    let jwt = jwt::Verifier::from_config(&config).await;
+    if jwt.is_some() {
+        log::info!("✓ JWT auth enabled");
+    }
+
+    let (events, _) = broadcast::channel(256);
+    let processing = Arc::new(Semaphore::new(config.max_concurrent_processing));
+    let outbound = Semaphore::new(config.max_outbound_concurrency);
 
     let state = web::Data::new(AppState {
         config,
         s3,
-        db: collection,
+        images,
+        pending,
+        access_log,
         redis: redis_client,
+        events,
+        processing,
+        outbound,
+        outbound_hosts: Default::default(),
+        image_count: AtomicU64::new(0),
+        image_count_at: AtomicI64::new(0),
+        s3_failures: AtomicU32::new(0),
+        s3_open_until: AtomicI64::new(0),
+        key_in_flight: Default::default(),
+        lookups: Default::default(),
+        jobs,
+        backfill_running: AtomicBool::new(false),
+        orphans,
+        jwt,
     });
 
+    // A one-shot deployment check: exit with the result instead of serving
+    if state.config.selftest {
+        match handlers::self_test(&state).await {
+            Ok(()) => {
+                log::info!("✓ Self-test passed");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                log::error!("❌ Self-test failed: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Pick up rotated signing keys without a restart
+    if state.jwt.is_some() && state.config.jwt_public_key.is_none() {
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(JWKS_REFRESH_SECS));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Some(jwt) = &state.jwt {
+                    jwt.refresh().await;
+                }
+            }
+        });
+    }
+
+    // Delete images whose retention has run out; lookups already hide them until then
+    {
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(EXPIRY_SWEEP_SECS));
+            loop {
+                interval.tick().await;
+                handlers::sweep_expired(&state).await;
+            }
+        });
+    }
+
+    // Move old originals to the cheaper bucket
+    if state.config.r2_cold_bucket.is_some() {
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(COLD_MIGRATION_SECS));
+            loop {
+                interval.tick().await;
+                handlers::migrate_cold(&state).await;
+            }
+        });
+    }
+
     log::info!("🚀 Ready on 0.0.0.0:{port}");
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(["GET", "POST", "PUT", "OPTIONS"])
-            .allowed_headers(["Content-Type"])
-            .max_age(3600);
+    let trusted_proxies = state.config.trusted_proxies.clone();
+    let route_prefix = state.config.route_prefix.clone();
+    let force_https = state.config.force_https;
+    let hsts = HeaderValue::from_str(&format!("max-age={}", state.config.hsts_max_age_secs))
+        .expect("HSTS header value");
+    let probe_paths = [format!("{route_prefix}/health"), format!("{route_prefix}/healthz")];
+
+    let mut server = HttpServer::new(move || {
+        let https_proxies = trusted_proxies.clone();
+        let hsts = hsts.clone();
+        let probe_paths = probe_paths.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        // Default actix format, with the peer address swapped for the proxy-aware client IP
+        let logger = Logger::new(r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+            .custom_request_replace("client_ip", move |req| {
+                net::client_ip(req.request(), &trusted_proxies)
+                    .map_or_else(|| "-".into(), |ip| ip.to_string())
+            });
 
         App::new()
-            .wrap(Logger::default())
-            .wrap(cors)
+            // Bound handler execution; the dropped future cancels any in-flight S3/Mongo/Redis work
+            .wrap_fn(move |req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    match actix_web::rt::time::timeout(request_timeout, fut).await {
+                        Ok(res) => res,
+                        Err(_) => Err(AppError::Timeout(format!(
+                            "Request exceeded {}s",
+                            request_timeout.as_secs()
+                        ))
+                        .into()),
+                    }
+                }
+            })
+            // Tiny and streamed (SSE) bodies aren't worth compressing: an `identity` marker makes
+            // `Compress` pass them through, and is dropped again once it has
+            .wrap_fn(move |req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    let small = match res.response().body().size() {
+                        BodySize::Sized(n) => n < compress_min_bytes,
+                        _ => true,
+                    };
+                    if small && !res.headers().contains_key(header::CONTENT_ENCODING) {
+                        res.headers_mut()
+                            .insert(header::CONTENT_ENCODING, HeaderValue::from_static(IDENTITY));
+                    }
+                    Ok(res)
+                }
+            })
+            .wrap(Compress::default())
+            .wrap_fn(|req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    if res
+                        .headers()
+                        .get(header::CONTENT_ENCODING)
+                        .is_some_and(|v| v == IDENTITY)
+                    {
+                        res.headers_mut().remove(header::CONTENT_ENCODING);
+                    }
+                    Ok(res)
+                }
+            })
+            // FORCE_HTTPS: plain HTTP is redirected (308 keeps the method and body of writes), HTTPS
+            // answers carry HSTS; probes are exempt so load balancers can keep checking over HTTP
+            .wrap_fn(move |req, srv| {
+                let enforce = force_https && !probe_paths.iter().any(|p| p == req.path());
+                if enforce && !net::is_https(req.request(), &https_proxies) {
+                    let target = format!(
+                        "https://{}{}",
+                        req.connection_info().host(),
+                        req.uri().path_and_query().map_or("/", |pq| pq.as_str())
+                    );
+                    let status = if matches!(*req.method(), Method::GET | Method::HEAD) {
+                        StatusCode::MOVED_PERMANENTLY
+                    } else {
+                        StatusCode::PERMANENT_REDIRECT
+                    };
+                    let res = HttpResponse::build(status)
+                        .insert_header((header::LOCATION, target))
+                        .finish();
+                    return Either::Right(ready(Ok(req.into_response(res).map_into_right_body())));
+                }
+                let fut = srv.call(req);
+                let hsts = enforce.then(|| hsts.clone());
+                Either::Left(async move {
+                    let mut res = fut.await?;
+                    if let Some(hsts) = hsts {
+                        res.headers_mut().insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+                    }
+                    Ok(res.map_into_left_body())
+                })
+            })
+            .wrap(logger)
+            .wrap(cors())
             .app_data(state.clone())
-            .route("/transfer", web::post().to(handlers::create_transfer))
-            .route(
-                "/transfer/{id}/done",
-                web::post().to(handlers::complete_transfer),
+            .service(
+                web::scope(&route_prefix)
+                    // "" answers the bare prefix (`/api`), "/" the root or `/api/`
+                    .route("", web::get().to(handlers::index))
+                    .route("/", web::get().to(handlers::index))
+                    .route("/transfer", web::post().to(handlers::create_transfer))
+                    .route(
+                        "/transfer/{id}/done",
+                        web::post().to(handlers::complete_transfer),
+                    )
+                    .route("/upload/{name}", web::put().to(handlers::stream_upload))
+                    .route("/mirror", web::post().to(handlers::mirror_image))
+                    .route("/i", web::get().to(handlers::list_images))
+                    .route("/i/delete", web::post().to(handlers::bulk_delete))
+                    .route("/i/{id}", web::get().to(handlers::get_image))
+                    .route("/i/{id}", web::head().to(handlers::head_image))
+                    .route("/i/{id}", web::delete().to(handlers::delete_image))
+                    .route("/i/{id}/rename", web::post().to(handlers::rename_image))
+                    .route("/i/{id}/similar", web::get().to(handlers::similar_images))
+                    .route("/i/{id}/rotate-id", web::post().to(handlers::rotate_image_id))
+                    .route("/admin/pending", web::get().to(handlers::pending_transfers))
+                    .route("/admin/stale", web::get().to(handlers::stale_images))
+                    .route("/admin/usage", web::get().to(handlers::tenant_usage))
+                    .route("/admin/backfill", web::post().to(handlers::start_backfill))
+                    .route("/admin/cache/warm", web::post().to(handlers::warm_cache))
+                    .route("/events", web::get().to(handlers::events))
+                    .route("/health", web::get().to(handlers::health))
+                    .route("/healthz", web::get().to(handlers::healthz)),
             )
-            .route("/i/{id}", web::get().to(handlers::get_image))
-            .route("/health", web::get().to(handlers::health))
-    })
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+    });
+    if let Some(keep_alive) = keep_alive {
+        server = server.keep_alive(keep_alive);
+    }
+    // h2c is negotiated by prior knowledge; HTTP/1.1 clients on the same port are unaffected
+    let server = if http2 {
+        log::info!("✓ HTTP/2 (h2c) enabled");
+        server.bind_auto_h2c(("0.0.0.0", port))?
+    } else {
+        server.bind(("0.0.0.0", port))?
+    };
+    server.run().await
+}
+
+/// CORS for every route: any origin, the API's own headers, and the image metadata headers exposed
+fn cors() -> Cors {
+    Cors::default()
+        .allow_any_origin()
+        .allowed_methods(["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS"])
+        .allowed_headers([
+            "Content-Type",
+            "Authorization",
+            auth::API_KEY_HEADER,
+            auth::DELETE_TOKEN_HEADER,
+        ])
+        .expose_headers([
+            "ETag",
+            "X-Image-Size",
+            "X-Image-Timestamp",
+            "X-Image-Type",
+            "X-Cache",
+            "Retry-After",
+        ])
+        .max_age(3600)
 }
@@ -0,0 +1,40 @@
+use exif::{In, Reader, Tag, Value};
+use std::io::Cursor;
+
+use crate::models::ImageMeta;
+
+/// Longest value kept for any single tag, so odd maker strings can't bloat documents
+const MAX_VALUE_LEN: usize = 128;
+
+/// Parses EXIF from the start of an image and keeps only the whitelisted tags.
+/// Returns `None` when the bytes carry no EXIF or none of the tags are present.
+pub fn extract(bytes: &[u8]) -> Option<ImageMeta> {
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()?;
+
+    let text = |tag: Tag| -> Option<String> {
+        let field = exif.get_field(tag, In::PRIMARY)?;
+        let value = match &field.value {
+            Value::Ascii(parts) => parts
+                .iter()
+                .map(|p| String::from_utf8_lossy(p).trim().to_string())
+                .find(|s| !s.is_empty())?,
+            _ => field.display_value().with_unit(&exif).to_string(),
+        };
+        Some(value.chars().take(MAX_VALUE_LEN).collect())
+    };
+
+    let meta = ImageMeta {
+        make: text(Tag::Make),
+        model: text(Tag::Model),
+        lens: text(Tag::LensModel),
+        taken: text(Tag::DateTimeOriginal),
+        exposure: text(Tag::ExposureTime),
+        aperture: text(Tag::FNumber),
+        iso: text(Tag::PhotographicSensitivity),
+        focal: text(Tag::FocalLength),
+    };
+
+    (!meta.is_empty()).then_some(meta)
+}
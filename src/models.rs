@@ -1,34 +1,205 @@
 use actix_web::{http::StatusCode, HttpResponse, ResponseError};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::sync::OnceLock;
+
+/// Set once at startup from `BOOL_OK`; errors are rendered without access to the config
+static BOOL_OK: OnceLock<bool> = OnceLock::new();
+
+pub fn set_bool_ok(enabled: bool) {
+    let _ = BOOL_OK.set(enabled);
+}
+
+/// The `ok` field of every response: `1`/`0` by default, `true`/`false` with `BOOL_OK=true`
+#[derive(Clone, Copy)]
+pub struct OkFlag(pub bool);
+
+impl Serialize for OkFlag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if BOOL_OK.get().copied().unwrap_or(false) {
+            serializer.serialize_bool(self.0)
+        } else {
+            serializer.serialize_u8(u8::from(self.0))
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct TransferRequest {
     pub name: String,
     pub size: u64,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default)]
     pub content_type: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// Caller ids (API key ids or `jwt:{sub}`) allowed to look the image up; empty = public
+    #[serde(default)]
+    pub acl: Vec<String>,
+    /// Seconds until the image is deleted; unset falls back to `RETENTION_POLICY`
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    /// CRC32C of the file, base64 as in `x-amz-checksum-crc32c`; signed into the upload URL
+    #[serde(default)]
+    pub crc32c: Option<String>,
+}
+
+/// Metadata for `PUT /upload/{name}`, whose body is the file itself
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    #[serde(default)]
+    pub description: String,
+    /// A JSON object of string tags, e.g. `{"album":"trip"}`
+    pub tags: Option<String>,
+    /// Comma-separated caller ids allowed to look the image up; unset = public
+    pub acl: Option<String>,
+    /// Seconds until the image is deleted; unset falls back to `RETENTION_POLICY`
+    pub expires_in_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct BulkDeleteRequest {
+    pub ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CacheWarmRequest {
+    pub ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// `key:value` exact tag match
+    pub tag: Option<String>,
+    pub limit: Option<u32>,
+    /// Cursor: return ids strictly after this one
+    pub after: Option<String>,
+    /// Comma-separated payload fields to return; `id` is always included
+    pub fields: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct StaleQuery {
+    /// Images not served for at least this many days
+    pub days: u32,
+    pub limit: Option<u32>,
+    /// Cursor: return ids strictly after this one
+    pub after: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    /// Caller id the images were uploaded with: an API key id or `jwt:{sub}`
+    pub tenant: String,
+}
+
+#[derive(Deserialize)]
+pub struct SimilarQuery {
+    /// Max Hamming distance between perceptual hashes
+    pub distance: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct MirrorRequest {
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ImageQuery {
+    pub redirect: Option<String>,
+    pub inline: Option<String>,
+}
+
+impl ImageQuery {
+    /// `?redirect=1` or `?redirect=true` asks for a 302 to the object URL
+    pub fn redirect(&self) -> bool {
+        matches!(self.redirect.as_deref(), Some("1" | "true"))
+    }
+
+    /// `?inline=1` or `?inline=true` asks for the bytes as a `data:` URI in the payload
+    pub fn inline(&self) -> bool {
+        matches!(self.inline.as_deref(), Some("1" | "true"))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PendingTransfer {
     pub key: String,
     pub size: u64,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// The client's file name as sent, with `SAFE_KEYS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orig_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl: Vec<String>,
+    /// Caller that requested the transfer (key id or `jwt:{sub}`), stored as the image's tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+    /// CRC32C of the stored object, base64 as R2 reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32c: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct TransferResponse {
-    pub ok: u8,
+    pub ok: OkFlag,
     pub id: String,
     #[serde(rename = "uploadUrl")]
     pub upload_url: String,
+    /// Headers the client must send on the PUT exactly as given (signed into `uploadUrl`)
+    #[serde(rename = "uploadHeaders")]
+    pub upload_headers: BTreeMap<String, String>,
+    pub key: String,
+    /// Public URL of `key`; left out with `CONTENT_ADDRESSED`, where completion moves the object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+// Broadcast to `/events` subscribers after a successful completion
+#[derive(Serialize, Clone)]
+pub struct TransferEvent {
+    pub id: String,
     pub key: String,
+    pub size: u64,
+    /// Uploader and `acl` decide who the event is streamed to; neither is sent
+    #[serde(skip)]
+    pub owner: Option<String>,
+    #[serde(skip)]
+    pub acl: Vec<String>,
+}
+
+impl TransferEvent {
+    /// Subscribers only see their own uploads and those shared with them
+    pub fn visible_to(&self, caller: &str) -> bool {
+        self.owner.as_deref() == Some(caller) || self.acl.iter().any(|c| c == caller)
+    }
 }
 
 // Internal representation for AES-GCM encryption
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ImageResponsePayload {
     pub url: String,
+    /// Both set only with `R2_CDN_DOMAIN`; `url` then equals `cdn_url`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cdn_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_url: Option<String>,
     pub f: String,
     pub s: f64,
     pub t: i64,
@@ -37,13 +208,172 @@ pub struct ImageResponsePayload {
     pub p: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub c: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ImageMeta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub h: Option<u32>,
+    /// Dominant color as `#rrggbb`, for loading placeholders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Client-supplied description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    /// Client's file name when `SAFE_KEYS` stored it under a different key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orig_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<ImageVariant>,
+    /// Full-size WebP copy, rendered on first request with `WEBP_NEGOTIATION`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webp: Option<String>,
+    /// Callers allowed to look the image up; cached for enforcement, never sent to clients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl: Vec<String>,
+    /// Unix second after which the image is gone, when it has an expiry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    /// Bucket holding the original when it isn't `R2_BUCKET`; cached, never sent to clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub b: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tags: HashMap<String, String>,
+}
+
+// `VERBOSE_KEYS=true` view of the payload; the cache always keeps the terse form
+#[derive(Serialize)]
+pub struct VerbosePayload<'a> {
+    pub url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cdn_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin_url: Option<&'a str>,
+    pub filename: &'a str,
+    pub size: f64,
+    pub timestamp: i64,
+    pub description: &'a str,
+    pub password: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<&'a ImageMeta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub variants: &'a [ImageVariant],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webp_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub tags: &'a HashMap<String, String>,
+}
+
+impl ImageResponsePayload {
+    pub fn verbose(&self) -> VerbosePayload<'_> {
+        VerbosePayload {
+            url: &self.url,
+            cdn_url: self.cdn_url.as_deref(),
+            origin_url: self.origin_url.as_deref(),
+            filename: &self.f,
+            size: self.s,
+            timestamp: self.t,
+            description: &self.d,
+            password: &self.p,
+            cached: self.c,
+            meta: self.meta.as_ref(),
+            width: self.w,
+            height: self.h,
+            color: self.color.as_deref(),
+            caption: self.desc.as_deref(),
+            original_name: self.orig_name.as_deref(),
+            variants: &self.variants,
+            webp_url: self.webp.as_deref(),
+            expires_at: self.exp,
+            tags: &self.tags,
+        }
+    }
+}
+
+// One entry of the `GET /i` listing, with the payload already in the configured key scheme
+#[derive(Serialize)]
+pub struct ListItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub payload: serde_json::Value,
+}
+
+// Pre-generated WebP rendition, for building a `srcset`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageVariant {
+    pub w: u32,
+    pub h: u32,
+    pub url: String,
+}
+
+// Whitelisted EXIF fields, stored as the `meta` sub-document
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ImageMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taken: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aperture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal: Option<String>,
+}
+
+impl ImageMeta {
+    pub fn is_empty(&self) -> bool {
+        self.make.is_none()
+            && self.model.is_none()
+            && self.lens.is_none()
+            && self.taken.is_none()
+            && self.exposure.is_none()
+            && self.aperture.is_none()
+            && self.iso.is_none()
+            && self.focal.is_none()
+    }
 }
 
 // What the client actually receives
 #[derive(Serialize)]
 pub struct ObfuscatedResponse {
-    pub ok: u8,
+    pub ok: OkFlag,
     pub payload: String,
+    /// Text encoding of `payload`; always `"hex"` today
+    pub enc: &'static str,
+    /// Decoded ciphertext length in bytes, so clients can preallocate and detect truncation
+    pub len: usize,
+    /// The decrypted bytes are gzip and must be decompressed before parsing (`COMPRESS_PAYLOAD`)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+}
+
+// `GET /i/{id}` with `OBFUSCATE=false`: the payload itself, next to `ok`
+#[derive(Serialize)]
+pub struct PlainResponse {
+    pub ok: OkFlag,
+    #[serde(flatten)]
+    pub payload: serde_json::Value,
 }
 
 #[derive(Debug)]
@@ -52,6 +382,18 @@ pub enum AppError {
     NotFound(String),
     Internal(String),
     LargePayload(String),
+    Timeout(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    Unprocessable(String),
+    Unavailable(String),
+    InsufficientStorage(String),
+    TooManyRequests(String),
+    /// A backend outage: `503` with `Retry-After` set to the seconds given
+    Outage(String, u64),
+    /// Several problems reported together as `details`
+    Validation(Vec<AppError>),
 }
 
 impl fmt::Display for AppError {
@@ -61,6 +403,19 @@ impl fmt::Display for AppError {
             Self::NotFound(e) => write!(f, "Not Found: {e}"),
             Self::Internal(e) => write!(f, "Internal Error: {e}"),
             Self::LargePayload(e) => write!(f, "Payload Too Large: {e}"),
+            Self::Timeout(e) => write!(f, "Gateway Timeout: {e}"),
+            Self::Unauthorized(e) => write!(f, "Unauthorized: {e}"),
+            Self::Forbidden(e) => write!(f, "Forbidden: {e}"),
+            Self::Conflict(e) => write!(f, "Conflict: {e}"),
+            Self::Unprocessable(e) => write!(f, "Unprocessable: {e}"),
+            Self::Unavailable(e) => write!(f, "Service Unavailable: {e}"),
+            Self::InsufficientStorage(e) => write!(f, "Insufficient Storage: {e}"),
+            Self::TooManyRequests(e) => write!(f, "Too Many Requests: {e}"),
+            Self::Outage(e, _) => write!(f, "Service Unavailable: {e}"),
+            Self::Validation(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", messages.join("; "))
+            }
         }
     }
 }
@@ -72,13 +427,35 @@ impl ResponseError for AppError {
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::LargePayload(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Unavailable(_) | Self::Outage(..) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            // A lone problem keeps its own status (e.g. 413), several are a plain 400
+            Self::Validation(errors) => match errors.as_slice() {
+                [only] => only.status_code(),
+                _ => StatusCode::BAD_REQUEST,
+            },
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(serde_json::json!({
-            "ok": 0,
+        let mut body = serde_json::json!({
+            "ok": OkFlag(false),
             "e": self.to_string(),
-        }))
+        });
+        if let Self::Validation(errors) = self {
+            let details: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            body["details"] = details.into();
+        }
+        let mut res = HttpResponse::build(self.status_code());
+        if let Self::Outage(_, retry_after) = self {
+            res.insert_header((actix_web::http::header::RETRY_AFTER, retry_after.to_string()));
+        }
+        res.json(body)
     }
 }
@@ -0,0 +1,76 @@
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Parses one `X-Forwarded-For` / `Forwarded: for=` node: bare IPs, `ip:port`, and `"[v6]:port"`
+fn parse_node(raw: &str) -> Option<IpAddr> {
+    let node = raw.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|s| s.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|rest| rest.split_once(']'))
+                .and_then(|(ip, _)| ip.parse().ok())
+        })
+}
+
+/// Forwarding chain from the request headers, nearest hop last
+fn forwarded_chain(req: &HttpRequest) -> Vec<IpAddr> {
+    let headers = req.headers();
+    let xff: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(parse_node)
+        .collect();
+    if !xff.is_empty() {
+        return xff;
+    }
+
+    headers
+        .get_all("forwarded")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split([',', ';']))
+        .filter_map(|pair| {
+            let (k, v) = pair.split_once('=')?;
+            k.trim().eq_ignore_ascii_case("for").then(|| parse_node(v))?
+        })
+        .collect()
+}
+
+/// Real client address. Forwarding headers are only honoured when the direct peer sits in a
+/// trusted CIDR; the chain is then walked from the nearest hop, skipping further trusted proxies.
+pub fn client_ip(req: &HttpRequest, trusted: &[IpNet]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+
+    let chain = forwarded_chain(req);
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or_else(|| chain.first())
+        .copied()
+        .or(Some(peer))
+}
+
+/// Whether the client reached the service over HTTPS: the first `X-Forwarded-Proto` entry when
+/// the direct peer is a trusted proxy, otherwise the connection itself
+pub fn is_https(req: &HttpRequest, trusted: &[IpNet]) -> bool {
+    let via_proxy = req
+        .peer_addr()
+        .is_some_and(|peer| trusted.iter().any(|net| net.contains(&peer.ip())));
+    let forwarded = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next());
+    match forwarded {
+        Some(proto) if via_proxy => proto.trim().eq_ignore_ascii_case("https"),
+        _ => req.app_config().secure(),
+    }
+}
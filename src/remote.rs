@@ -0,0 +1,164 @@
+use futures_util::StreamExt;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::models::AppError;
+
+/// Redirect hops followed, each re-validated like the original URL
+const MAX_REDIRECTS: usize = 3;
+
+/// A fetched remote object
+pub struct Fetched {
+    pub bytes: Vec<u8>,
+    /// `Content-Type` without parameters, lowercased
+    pub content_type: String,
+}
+
+/// Whether an address is reachable on the public internet. Loopback, private, link-local,
+/// CGNAT, documentation and other special-purpose ranges are refused so outbound fetches
+/// can't be pointed at the host, the cloud metadata service or the internal network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_public_v4(v4),
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_v4(v4);
+            }
+            let seg = v6.segments();
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (seg[0] & 0xfe00) == 0xfc00 // unique local fc00::/7
+                || (seg[0] & 0xffc0) == 0xfe80 // link-local fe80::/10
+                || (seg[0] == 0x2001 && seg[1] == 0x0db8) // documentation
+                || (seg[0] == 0x0064 && seg[1] == 0xff9b)) // NAT64 can reach IPv4 internals
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)) // CGNAT 100.64.0.0/10
+        || (a == 198 && (b == 18 || b == 19)) // benchmarking 198.18.0.0/15
+        || a >= 240)
+}
+
+/// Parses and checks a URL a client asked the server to fetch: HTTPS only (plain HTTP with
+/// `ALLOW_HTTP_OUTBOUND`), no embedded credentials, and a host name or public IP literal.
+pub fn validate_outbound_url(raw: &str, allow_http: bool) -> Result<Url, AppError> {
+    let url = Url::parse(raw).map_err(|_| AppError::BadRequest("Invalid URL".into()))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if allow_http => {}
+        _ => return Err(AppError::BadRequest("Only https URLs can be fetched".into())),
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(AppError::BadRequest("URLs with credentials are not allowed".into()));
+    }
+    match url.host() {
+        None => Err(AppError::BadRequest("URL has no host".into())),
+        Some(url::Host::Ipv4(ip)) if !is_public_v4(ip) => Err(blocked()),
+        Some(url::Host::Ipv6(ip)) if !is_public(IpAddr::V6(ip)) => Err(blocked()),
+        Some(_) => Ok(url),
+    }
+}
+
+fn blocked() -> AppError {
+    AppError::BadRequest("URL points at a private or reserved address".into())
+}
+
+/// Resolves the URL's host and requires every answer to be public, returning the address the
+/// request is then pinned to so a second lookup can't be rebound to an internal one
+async fn resolve_public(url: &Url) -> Result<SocketAddr, AppError> {
+    let host = url.host_str().ok_or_else(|| AppError::BadRequest("URL has no host".into()))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| AppError::BadRequest(format!("Could not resolve {host}")))?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|a| !is_public(a.ip())) {
+        return Err(blocked());
+    }
+    Ok(addrs[0])
+}
+
+/// GETs a validated URL with at most `max_bytes` of body, following up to `MAX_REDIRECTS`
+/// redirects, each of which is validated and pinned like the first request
+pub async fn fetch(
+    url: Url,
+    allow_http: bool,
+    max_bytes: u64,
+    timeout: Duration,
+) -> Result<Fetched, AppError> {
+    let failed = |e: &dyn std::fmt::Display| AppError::Unprocessable(format!("Remote fetch failed: {e}"));
+    let mut url = url;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = resolve_public(&url).await?;
+        let host = url.host_str().unwrap_or_default().to_string();
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .resolve(&host, addr)
+            .timeout(timeout)
+            .build()
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
+        let resp = client.get(url.clone()).send().await.map_err(|e| failed(&e))?;
+
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| failed(&"redirect without Location"))?;
+            let next = url.join(location).map_err(|e| failed(&e))?;
+            url = validate_outbound_url(next.as_str(), allow_http)?;
+            continue;
+        }
+        if !resp.status().is_success() {
+            return Err(failed(&resp.status()));
+        }
+
+        let too_large = || AppError::LargePayload(format!("Remote file exceeds {max_bytes} bytes"));
+        if resp.content_length().is_some_and(|n| n > max_bytes) {
+            return Err(too_large());
+        }
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        // Content-Length can lie or be absent, so the cap is enforced while reading
+        let mut bytes = Vec::new();
+        let mut body = resp.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| failed(&e))?;
+            if (bytes.len() + chunk.len()) as u64 > max_bytes {
+                return Err(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        return Ok(Fetched {
+            bytes,
+            content_type,
+        });
+    }
+
+    Err(failed(&"too many redirects"))
+}
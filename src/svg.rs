@@ -0,0 +1,136 @@
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+/// Elements dropped with everything inside them: script hosts and ways to embed other documents
+const BLOCKED_ELEMENTS: &[&str] = &["script", "foreignobject", "iframe", "embed", "object", "handler"];
+
+/// Inline image types allowed in `href`; anything else (including nested SVG) could carry script
+const DATA_IMAGE_PREFIXES: &[&str] = &[
+    "data:image/png",
+    "data:image/jpeg",
+    "data:image/gif",
+    "data:image/webp",
+];
+
+/// Whether `href` only points inside the document or at an inert inline raster
+fn is_safe_href(value: &str) -> bool {
+    let value = value.trim().to_ascii_lowercase();
+    value.starts_with('#') || DATA_IMAGE_PREFIXES.iter().any(|p| value.starts_with(p))
+}
+
+/// Whether CSS can reach outside the document (`@import`, `url(...)` not pointing at a fragment).
+/// Entity and CSS escapes could hide either, so any `&` or `\` counts as external too.
+fn has_external_css(css: &str) -> bool {
+    let css = css.to_ascii_lowercase();
+    css.contains(['&', '\\'])
+        || css.contains("@import")
+        || css.contains("expression(")
+        || css.match_indices("url(").any(|(i, m)| {
+            !css[i + m.len()..]
+                .trim_start_matches(|c: char| c.is_whitespace() || c == '\'' || c == '"')
+                .starts_with('#')
+        })
+}
+
+/// Whether an attribute could run script or pull in an external resource
+fn is_unsafe_attribute(local: &str, value: &str) -> bool {
+    let local = local.to_ascii_lowercase();
+    let lowered = value.to_ascii_lowercase();
+    local.starts_with("on")
+        || (local == "href" && !is_safe_href(value))
+        || (local == "style" && has_external_css(value))
+        // `<animate attributeName="href" to="javascript:...">` rewrites a link after load
+        || (local == "attributename" && (lowered.trim().ends_with("href") || lowered.contains('&')))
+        || lowered.contains("javascript:")
+}
+
+/// Re-serializes an SVG without scripts, event handlers, DTDs or external references.
+/// Fails when the input isn't well-formed XML with an `<svg>` root.
+pub fn sanitize(input: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(input.len());
+    let mut open: Vec<&str> = Vec::new();
+    // Depth inside a blocked element; everything is dropped while > 0
+    let mut skip = 0usize;
+    let mut in_style = false;
+    let mut seen_root = false;
+
+    for token in Tokenizer::from(input) {
+        match token.map_err(|e| e.to_string())? {
+            Token::Declaration { span, .. } => out.push_str(&span),
+            // Stylesheet PIs, comments and DTDs (entity declarations) are all dropped
+            Token::ProcessingInstruction { .. }
+            | Token::Comment { .. }
+            | Token::DtdStart { .. }
+            | Token::EmptyDtd { .. }
+            | Token::EntityDeclaration { .. }
+            | Token::DtdEnd { .. } => {}
+            Token::ElementStart { local, span, .. } => {
+                if open.is_empty() {
+                    if seen_root {
+                        return Err("multiple root elements".into());
+                    }
+                    if !local.as_str().eq_ignore_ascii_case("svg") {
+                        return Err(format!("root element is <{}>, not <svg>", local.as_str()));
+                    }
+                    seen_root = true;
+                }
+                let name = &span.as_str()[1..];
+                open.push(name);
+                let lowered = local.as_str().to_ascii_lowercase();
+                if skip > 0 || BLOCKED_ELEMENTS.contains(&lowered.as_str()) {
+                    skip += 1;
+                } else {
+                    in_style = lowered == "style";
+                    out.push_str(&span);
+                }
+            }
+            Token::Attribute { local, value, span, .. } => {
+                if skip == 0 && !is_unsafe_attribute(&local, &value) {
+                    out.push(' ');
+                    out.push_str(&span);
+                }
+            }
+            Token::ElementEnd { end, span } => {
+                let closes = match end {
+                    ElementEnd::Open => false,
+                    ElementEnd::Empty => true,
+                    ElementEnd::Close(prefix, local) => {
+                        let name = if prefix.is_empty() {
+                            local.as_str().to_string()
+                        } else {
+                            format!("{}:{}", prefix.as_str(), local.as_str())
+                        };
+                        if open.last() != Some(&name.as_str()) {
+                            return Err(format!("unexpected </{name}>"));
+                        }
+                        true
+                    }
+                };
+                if skip == 0 {
+                    out.push_str(&span);
+                }
+                if closes {
+                    open.pop();
+                    in_style = false;
+                    skip = skip.saturating_sub(1);
+                }
+            }
+            Token::Text { text } | Token::Cdata { span: text, .. } => {
+                if skip > 0 || (in_style && has_external_css(&text)) {
+                    continue;
+                }
+                if open.is_empty() && !text.trim().is_empty() {
+                    return Err("text outside the root element".into());
+                }
+                out.push_str(&text);
+            }
+        }
+    }
+
+    if !seen_root {
+        return Err("no <svg> element".into());
+    }
+    if let Some(name) = open.last() {
+        return Err(format!("<{name}> is never closed"));
+    }
+    Ok(out)
+}